@@ -0,0 +1,45 @@
+//! Feed arbitrary bytes to both decoders.
+//!
+//! First byte picks the payload encoding, the rest is the stream from peer.
+
+#![no_main]
+
+use actix_codec::Decoder;
+use bytes::BytesMut;
+use libfuzzer_sys::fuzz_target;
+use tcp_chat::codec::{ChatCodec, ClientChatCodec, Compression, Encoding, Format, SharedEncoding};
+
+const MAX_FRAME_SIZE: usize = 64 * 1024;
+
+fn decode_all<D: Decoder>(mut codec: D, data: &[u8]) {
+    let mut buf = BytesMut::from(data);
+    loop {
+        match codec.decode(&mut buf) {
+            Ok(Some(_)) | Err(_) => (),
+            Ok(None) => return,
+        }
+    }
+}
+
+fuzz_target!(|data: &[u8]| {
+    let (selector, data) = match data.split_first() {
+        Some(split) => split,
+        None => return,
+    };
+    let encoding = SharedEncoding::default();
+    encoding.set(Encoding {
+        format: match selector % 3 {
+            0 => Format::Json,
+            1 => Format::MessagePack,
+            _ => Format::Cbor,
+        },
+        compression: if selector & 0x80 == 0 {
+            Compression::None
+        } else {
+            Compression::Deflate
+        },
+    });
+
+    decode_all(ChatCodec::new(MAX_FRAME_SIZE, encoding.clone()), data);
+    decode_all(ClientChatCodec::new(MAX_FRAME_SIZE, encoding), data);
+});
@@ -0,0 +1,218 @@
+//! Registration and login of user accounts.
+//!
+//! Passwords are stored as salted argon2 hashes. Hashing is slow on purpose,
+//! so it runs on the blocking thread pool, never inside `ChatServer`.
+//! Sessions are anonymous guests until they register or log in, from then on
+//! they go by their account name.
+//!
+//! Failed attempts make both the account and the address they came from wait
+//! before the next attempt, doubling the wait with every failure in a row, so
+//! passwords can't be guessed fast, not even by reconnecting.
+
+use std::fmt;
+use std::future::Future;
+use std::sync::OnceLock;
+
+use actix::prelude::*;
+use actix_web::web;
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use rand::Rng;
+
+use tcp_chat::codec::ErrorCode;
+
+use crate::server::{self, ChatServer, RoomError};
+
+/// Shortest password accepted on registration, in characters
+const MIN_PASSWORD_LENGTH: usize = 8;
+/// Longest password accepted, in characters
+const MAX_PASSWORD_LENGTH: usize = 1024;
+
+/// Why registration or login failed
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AccountError {
+    /// User name is not a valid nickname
+    InvalidName,
+    /// Password is too short or too long
+    InvalidPassword,
+    /// Account exists already, or another session goes by the name
+    Taken,
+    /// Unknown user or wrong password, deliberately not telling which
+    InvalidCredentials,
+    /// Session is logged in already, or account is in use by another session
+    AlreadyLoggedIn,
+    /// Account or address of the session failed too often, it has to wait
+    /// before trying again
+    TooManyAttempts,
+    /// Session sends requests too fast, flood protection refused this one
+    Throttled(RoomError),
+    /// Server failed to handle request
+    Internal,
+}
+
+impl AccountError {
+    /// Code reported to binary protocol peers
+    pub fn code(self) -> ErrorCode {
+        match self {
+            AccountError::InvalidName => ErrorCode::InvalidName,
+            AccountError::InvalidPassword => ErrorCode::InvalidRequest,
+            AccountError::Taken => ErrorCode::NameTaken,
+            AccountError::InvalidCredentials => ErrorCode::InvalidCredentials,
+            AccountError::AlreadyLoggedIn => ErrorCode::InvalidRequest,
+            AccountError::TooManyAttempts => ErrorCode::RateLimited,
+            AccountError::Throttled(e) => e.code(),
+            AccountError::Internal => ErrorCode::Internal,
+        }
+    }
+}
+
+impl fmt::Display for AccountError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AccountError::InvalidName => write!(f, "{}", server::NameError::Invalid),
+            AccountError::InvalidPassword => write!(
+                f,
+                "password must be {} to {} characters",
+                MIN_PASSWORD_LENGTH, MAX_PASSWORD_LENGTH
+            ),
+            AccountError::Taken => write!(f, "name is already taken"),
+            AccountError::InvalidCredentials => write!(f, "wrong user name or password"),
+            AccountError::AlreadyLoggedIn => write!(f, "already logged in"),
+            AccountError::TooManyAttempts => {
+                write!(f, "too many failed attempts, try again later")
+            }
+            AccountError::Throttled(e) => write!(f, "{}", e),
+            AccountError::Internal => write!(f, "could not handle account request"),
+        }
+    }
+}
+
+impl From<MailboxError> for AccountError {
+    fn from(_: MailboxError) -> Self {
+        AccountError::Internal
+    }
+}
+
+/// Create account `user` and log session `id` in to it, returns account name
+pub async fn register(
+    server: Addr<ChatServer>,
+    id: usize,
+    user: String,
+    password: String,
+) -> Result<String, AccountError> {
+    let res = create(server.clone(), id, user.clone(), password);
+    attempt(server, id, user, res).await
+}
+
+/// Log session `id` in to account `user`, returns account name as registered
+pub async fn login(
+    server: Addr<ChatServer>,
+    id: usize,
+    user: String,
+    password: String,
+) -> Result<String, AccountError> {
+    let res = authenticate(server.clone(), id, user.clone(), password);
+    attempt(server, id, user, res).await
+}
+
+/// Run register or login `res` for account `user` unless session `id` has
+/// to wait, and tell server how it went
+async fn attempt(
+    server: Addr<ChatServer>,
+    id: usize,
+    user: String,
+    res: impl Future<Output = Result<String, AccountError>>,
+) -> Result<String, AccountError> {
+    server
+        .send(server::AttemptLogin {
+            id,
+            user: user.clone(),
+        })
+        .await??;
+    let res = res.await;
+    server.do_send(server::LoginAttempted {
+        id,
+        user,
+        succeeded: res.is_ok(),
+    });
+    res
+}
+
+async fn create(
+    server: Addr<ChatServer>,
+    id: usize,
+    user: String,
+    password: String,
+) -> Result<String, AccountError> {
+    if !server::valid_name(&user) {
+        return Err(AccountError::InvalidName);
+    }
+    if !(MIN_PASSWORD_LENGTH..=MAX_PASSWORD_LENGTH).contains(&password.chars().count()) {
+        return Err(AccountError::InvalidPassword);
+    }
+
+    let hash = web::block(move || hash_password(&password))
+        .await
+        .map_err(|_| AccountError::Internal)?;
+    server
+        .send(server::Register {
+            id,
+            user: user.clone(),
+            hash,
+        })
+        .await??;
+    Ok(user)
+}
+
+async fn authenticate(
+    server: Addr<ChatServer>,
+    id: usize,
+    user: String,
+    password: String,
+) -> Result<String, AccountError> {
+    if password.chars().count() > MAX_PASSWORD_LENGTH {
+        return Err(AccountError::InvalidCredentials);
+    }
+    let hash = server
+        .send(server::PasswordHash { user: user.clone() })
+        .await?;
+
+    // unknown users take as long as wrong passwords, so they can't be told apart
+    let valid = web::block(move || match hash {
+        Some(hash) => verify_password(&password, &hash),
+        None => {
+            let _ = verify_password(&password, dummy_hash());
+            Ok(false)
+        }
+    })
+    .await
+    .map_err(|_| AccountError::Internal)?;
+    if !valid {
+        return Err(AccountError::InvalidCredentials);
+    }
+    server.send(server::Login { id, user }).await?
+}
+
+/// Salted hash of `password`, in PHC string format
+pub fn hash_password(password: &str) -> Result<String, AccountError> {
+    let salt: [u8; 16] = rand::thread_rng().gen();
+    let salt = SaltString::encode_b64(&salt).map_err(|_| AccountError::Internal)?;
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map(|hash| hash.to_string())
+        .map_err(|_| AccountError::Internal)
+}
+
+/// Hash of no account's password, checked instead when there is no account
+fn dummy_hash() -> &'static str {
+    static HASH: OnceLock<String> = OnceLock::new();
+    HASH.get_or_init(|| hash_password("not a password").unwrap_or_default())
+}
+
+/// Whether `password` matches `hash` made by `hash_password`
+pub fn verify_password(password: &str, hash: &str) -> Result<bool, AccountError> {
+    let hash = PasswordHash::new(hash).map_err(|_| AccountError::Internal)?;
+    Ok(Argon2::default()
+        .verify_password(password.as_bytes(), &hash)
+        .is_ok())
+}
@@ -1,5 +1,5 @@
-use std::{io, net, thread};
-use std::io::Error;
+use std::collections::HashSet;
+use std::{io, iter, net, thread};
 use std::str::FromStr;
 use std::time::Duration;
 
@@ -8,10 +8,10 @@ use tokio::io::{split, WriteHalf};
 use tokio::net::TcpStream;
 use tokio_util::codec::FramedRead;
 
-use crate::client::codec::ChatResponse;
-
-mod codec;
+use tcp_chat::codec::{self, ChatResponse, Escaped};
 
+/// Number of messages fetched by one `/history` command
+const HISTORY_PAGE_SIZE: usize = 20;
 
 #[actix_web::main]
 async fn main() {
@@ -24,9 +24,18 @@ async fn main() {
 
     let addr = ChatClient::create(|ctx| {
         let (r, w) = split(stream);
-        ChatClient::add_stream(FramedRead::new(r, codec::ClientChatCodec), ctx);
+        let encoding = codec::SharedEncoding::default();
+        let codec = codec::ClientChatCodec::new(codec::DEFAULT_MAX_FRAME_SIZE, encoding.clone());
+        ChatClient::add_stream(FramedRead::new(r, codec), ctx);
+        let codec = codec::ClientChatCodec::new(codec::DEFAULT_MAX_FRAME_SIZE, encoding.clone());
         ChatClient {
-            framed: actix::io::FramedWrite::new(w, codec::ClientChatCodec, ctx),
+            framed: actix::io::FramedWrite::new(w, codec, ctx),
+            encoding,
+            session_id: 0,
+            rooms: iter::once("Main".to_owned()).collect(),
+            room: Some("Main".to_owned()),
+            last_request: 0,
+            last_message: None,
         }
     });
 
@@ -43,10 +52,22 @@ async fn main() {
 
 struct ChatClient {
     framed: actix::io::FramedWrite<
-        codec::ChatRequest,
+        codec::Request,
         WriteHalf<TcpStream>,
         codec::ClientChatCodec,
     >,
+    /// payload encoding, switched once server welcomed us
+    encoding: codec::SharedEncoding,
+    /// our session id, known once server welcomed us
+    session_id: usize,
+    /// rooms we are in
+    rooms: HashSet<String>,
+    /// room plain messages go to and `/history` pages through
+    room: Option<String>,
+    /// id of the latest request we wanted a reply to
+    last_request: u64,
+    /// id of the latest message we sent, what `/edit` and `/delete` act on
+    last_message: Option<u64>,
 }
 
 #[derive(Message)]
@@ -57,6 +78,16 @@ impl Actor for ChatClient {
     type Context = Context<Self>;
 
     fn started(&mut self, ctx: &mut Self::Context) {
+        // introduce ourselves, server ignores everything else until then
+        self.framed.write(
+            codec::ChatRequest::Hello {
+                version: codec::PROTOCOL_VERSION,
+                client_name: "websocket-tcp-client".to_owned(),
+                capabilities: vec![codec::Compression::Deflate.capability().to_owned()],
+            }
+            .into(),
+        );
+
         // start heartbeats otherwise server will disconnect after 10 seconds
         self.hb(ctx)
     }
@@ -71,7 +102,7 @@ impl Actor for ChatClient {
 impl ChatClient {
     fn hb(&self, ctx: &mut Context<Self>) {
         ctx.run_later(Duration::new(1, 0), |act, ctx| {
-            act.framed.write(codec::ChatRequest::Ping);
+            act.framed.write(codec::ChatRequest::Ping.into());
             act.hb(ctx);
 
             // client should also check for a timeout here, similar to the server code
@@ -79,6 +110,11 @@ impl ChatClient {
     }
 }
 
+/// Name of a session, `#id` if it has none
+fn who(session: usize, name: Option<String>) -> String {
+    name.unwrap_or_else(|| format!("#{}", session))
+}
+
 impl actix::io::WriteHandler<io::Error> for ChatClient {}
 
 /// Handle stdin commands
@@ -95,42 +131,450 @@ impl Handler<ClientCommand> for ChatClient {
             let v: Vec<&str> = m.splitn(2, ' ').collect();
             match v[0] {
                 "/list" => {
-                    self.framed.write(codec::ChatRequest::List);
+                    self.framed.write(codec::ChatRequest::List.into());
                 }
-                "/join" => {
+                "/join" => match v.get(1) {
+                    Some(args) => {
+                        // password, if any, is the rest of the line
+                        let (room, password) = match args.split_once(' ') {
+                            Some((room, password)) => (room, Some(password.trim().to_owned())),
+                            None => (*args, None),
+                        };
+                        self.framed.write(
+                            codec::ChatRequest::Join {
+                                room: room.to_owned(),
+                                password,
+                                visibility: None,
+                            }
+                            .into(),
+                        );
+                    }
+                    None => println!("!!! room name is required"),
+                },
+                "/create" => {
+                    let args: Vec<&str> =
+                        v.get(1).map_or(Vec::new(), |a| a.splitn(3, ' ').collect());
+                    let visibility = match args.get(1).map(|v| v.parse()) {
+                        Some(Ok(visibility)) => visibility,
+                        Some(Err(e)) => {
+                            println!("!!! {}", e);
+                            return;
+                        }
+                        None => {
+                            println!("!!! room name and visibility are required");
+                            return;
+                        }
+                    };
+                    self.framed.write(
+                        codec::ChatRequest::Join {
+                            room: args[0].to_owned(),
+                            password: args.get(2).map(|p| p.trim().to_owned()),
+                            visibility: Some(visibility),
+                        }
+                        .into(),
+                    );
+                }
+                "/leave" => {
+                    // current room unless told otherwise
+                    let name = match (v.get(1), &self.room) {
+                        (Some(name), _) => (*name).to_owned(),
+                        (None, Some(room)) => room.clone(),
+                        (None, None) => {
+                            println!("!!! room name is required");
+                            return;
+                        }
+                    };
+                    self.framed.write(codec::ChatRequest::Leave(name).into());
+                }
+                "/room" => {
                     if v.len() == 2 {
-                        self.framed.write(codec::ChatRequest::Join(v[1].to_owned()));
+                        if self.rooms.contains(v[1]) {
+                            self.room = Some(v[1].to_owned());
+                        } else {
+                            println!("!!! not in room: {}", v[1]);
+                        }
                     } else {
                         println!("!!! room name is required");
                     }
                 }
+                "/name" => {
+                    if v.len() == 2 {
+                        self.framed
+                            .write(codec::ChatRequest::SetName(v[1].to_owned()).into());
+                    } else {
+                        println!("!!! name is required");
+                    }
+                }
+                "/register" | "/login" => {
+                    let (user, password) = match v.get(1).and_then(|a| a.split_once(' ')) {
+                        Some((user, password)) => (user.to_owned(), password.to_owned()),
+                        None => {
+                            println!("!!! user name and password are required");
+                            return;
+                        }
+                    };
+                    let request = if v[0] == "/register" {
+                        codec::ChatRequest::Register { user, password }
+                    } else {
+                        codec::ChatRequest::Login { user, password }
+                    };
+                    self.framed.write(request.into());
+                }
+                "/msg" => {
+                    let (to, body) = match v.get(1).and_then(|a| a.split_once(' ')) {
+                        Some((to, body)) => (to.to_owned(), body.trim().to_owned()),
+                        None => {
+                            println!("!!! name and message are required");
+                            return;
+                        }
+                    };
+                    self.framed
+                        .write(codec::ChatRequest::DirectMessage { to, body }.into());
+                }
+                "/kick" | "/ban" | "/unban" | "/mute" | "/unmute" | "/invite" | "/mod"
+                | "/unmod" => {
+                    let user = match v.get(1) {
+                        Some(user) => user.trim().to_owned(),
+                        None => {
+                            println!("!!! user name is required");
+                            return;
+                        }
+                    };
+                    let room = match &self.room {
+                        Some(room) => room.clone(),
+                        None => {
+                            println!("!!! join a room first");
+                            return;
+                        }
+                    };
+                    let request = match v[0] {
+                        "/kick" => codec::ChatRequest::Kick { room, user },
+                        "/ban" => codec::ChatRequest::Ban { room, user },
+                        "/unban" => codec::ChatRequest::Unban { room, user },
+                        "/mute" => codec::ChatRequest::Mute { room, user },
+                        "/unmute" => codec::ChatRequest::Unmute { room, user },
+                        "/invite" => codec::ChatRequest::Invite { room, user },
+                        "/mod" => codec::ChatRequest::AddModerator { room, user },
+                        _ => codec::ChatRequest::RemoveModerator { room, user },
+                    };
+                    self.framed.write(request.into());
+                }
+                "/visibility" | "/password" => {
+                    let room = match &self.room {
+                        Some(room) => room.clone(),
+                        None => {
+                            println!("!!! join a room first");
+                            return;
+                        }
+                    };
+                    let arg = v.get(1).map(|a| a.trim().to_owned());
+                    let request = if v[0] == "/visibility" {
+                        let visibility = match arg.map(|v| v.parse()) {
+                            Some(Ok(visibility)) => visibility,
+                            Some(Err(e)) => {
+                                println!("!!! {}", e);
+                                return;
+                            }
+                            None => {
+                                println!("!!! visibility is required");
+                                return;
+                            }
+                        };
+                        codec::ChatRequest::SetVisibility { room, visibility }
+                    } else {
+                        // no password opens the room again
+                        codec::ChatRequest::SetPassword {
+                            room,
+                            password: arg,
+                        }
+                    };
+                    self.framed.write(request.into());
+                }
+                "/topic" | "/describe" => {
+                    let room = match &self.room {
+                        Some(room) => room.clone(),
+                        None => {
+                            println!("!!! join a room first");
+                            return;
+                        }
+                    };
+                    // no text clears it
+                    let text = v.get(1).map(|t| t.trim().to_owned());
+                    let request = if v[0] == "/topic" {
+                        codec::ChatRequest::SetTopic { room, topic: text }
+                    } else {
+                        codec::ChatRequest::SetDescription {
+                            room,
+                            description: text,
+                        }
+                    };
+                    self.framed.write(request.into());
+                }
+                "/edit" => {
+                    let body = match v.get(1) {
+                        Some(body) => body.trim().to_owned(),
+                        None => {
+                            println!("!!! message is required");
+                            return;
+                        }
+                    };
+                    match self.last_message {
+                        Some(id) => self
+                            .framed
+                            .write(codec::ChatRequest::EditMessage { id, body }.into()),
+                        None => println!("!!! no message to edit"),
+                    }
+                }
+                "/delete" => {
+                    // own latest message unless told otherwise
+                    let id = match (v.get(1).map(|id| id.trim().parse()), self.last_message) {
+                        (Some(Ok(id)), _) => id,
+                        (Some(Err(_)), _) => {
+                            println!("!!! message id must be a number");
+                            return;
+                        }
+                        (None, Some(id)) => id,
+                        (None, None) => {
+                            println!("!!! message id is required");
+                            return;
+                        }
+                    };
+                    self.framed
+                        .write(codec::ChatRequest::DeleteMessage { id }.into());
+                }
+                "/who" => {
+                    // current room unless told otherwise
+                    let room = match (v.get(1), &self.room) {
+                        (Some(room), _) => (*room).to_owned(),
+                        (None, Some(room)) => room.clone(),
+                        (None, None) => {
+                            println!("!!! room name is required");
+                            return;
+                        }
+                    };
+                    self.framed.write(codec::ChatRequest::Who(room).into());
+                }
+                "/history" => {
+                    // page back with the id of the oldest message seen
+                    let before = match v.get(1).map(|id| id.trim().parse()) {
+                        None => None,
+                        Some(Ok(id)) => Some(id),
+                        Some(Err(_)) => {
+                            println!("!!! message id must be a number");
+                            return;
+                        }
+                    };
+                    let room = match &self.room {
+                        Some(room) => room.clone(),
+                        None => {
+                            println!("!!! join a room first");
+                            return;
+                        }
+                    };
+                    self.framed.write(
+                        codec::ChatRequest::History {
+                            room,
+                            before,
+                            limit: HISTORY_PAGE_SIZE,
+                        }
+                        .into(),
+                    );
+                }
                 _ => println!("!!! unkown command"),
             }
+        } else if let Some(room) = &self.room {
+            // correlated, so server tells the id of the message
+            self.last_request += 1;
+            self.framed.write(codec::Request {
+                id: Some(self.last_request),
+                body: codec::ChatRequest::Message {
+                    room: room.clone(),
+                    body: m.to_owned(),
+                },
+            });
         } else {
-            self.framed.write(codec::ChatRequest::Message(m.to_owned()));
+            println!("!!! join a room first");
         }
     }
 }
 
 
 // server communication
-impl StreamHandler<Result<codec::ChatResponse, io::Error>> for ChatClient {
-    fn handle(&mut self, msg: Result<ChatResponse, Error>, ctx: &mut Self::Context) {
+impl StreamHandler<Result<codec::ChatResponse, codec::CodecError>> for ChatClient {
+    fn handle(&mut self, msg: Result<ChatResponse, codec::CodecError>, ctx: &mut Self::Context) {
         match msg {
+            Ok(codec::ChatResponse::Welcome {
+                version,
+                session_id,
+                format,
+                compression,
+                ..
+            }) => {
+                self.encoding.set(codec::Encoding {
+                    format,
+                    compression,
+                });
+                self.session_id = session_id;
+                println!("!!! connected as {} (protocol {})", session_id, version);
+            }
             Ok(codec::ChatResponse::Message(ref msg)) => {
-                println!("message: {}", msg);
+                println!("{}", msg);
+            }
+            Ok(codec::ChatResponse::DirectMessage(ref msg)) => {
+                println!("{}", msg);
+            }
+            Ok(codec::ChatResponse::Sent { id, .. }) => {
+                self.last_message = Some(id);
+            }
+            Ok(codec::ChatResponse::MessageEdited(ref msg)) => {
+                println!("!!! edited: {}", msg);
+            }
+            Ok(codec::ChatResponse::MessageDeleted {
+                room,
+                id,
+                session,
+                name,
+            }) => println!(
+                "!!! {} deleted message {} in {}",
+                who(session, name),
+                id,
+                Escaped(&room)
+            ),
+            Ok(codec::ChatResponse::Joined { room, .. }) => {
+                println!("!!! joined: {}", Escaped(&room));
+                self.rooms.insert(room.clone());
+                self.room = Some(room);
+            }
+            Ok(codec::ChatResponse::Left { room, .. }) => {
+                println!("!!! left: {}", Escaped(&room));
+                self.rooms.remove(&room);
+                if self.room.as_ref() == Some(&room) {
+                    self.room = self.rooms.iter().next().cloned();
+                }
+            }
+            Ok(codec::ChatResponse::LoggedIn { user, .. }) => {
+                println!("!!! logged in as {}", user);
             }
-            Ok(codec::ChatResponse::Joined(ref msg)) => {
-                println!("!!! joined: {}", msg);
+            Ok(codec::ChatResponse::UserJoined {
+                room,
+                session,
+                name,
+            }) => println!("!!! {} joined {}", who(session, name), Escaped(&room)),
+            Ok(codec::ChatResponse::UserLeft {
+                room,
+                session,
+                name,
+            }) => println!("!!! {} left {}", who(session, name), Escaped(&room)),
+            Ok(codec::ChatResponse::UserDisconnected {
+                room,
+                session,
+                name,
+            }) => println!(
+                "!!! {} disconnected from {}",
+                who(session, name),
+                Escaped(&room)
+            ),
+            Ok(codec::ChatResponse::Renamed {
+                session,
+                old_name,
+                new_name,
+            }) => println!(
+                "!!! {} is now known as {}",
+                who(session, old_name),
+                new_name
+            ),
+            Ok(codec::ChatResponse::Kicked {
+                room,
+                session,
+                name,
+                banned,
+            }) => {
+                let how = if banned { "banned" } else { "kicked" };
+                println!(
+                    "!!! {} was {} from {}",
+                    who(session, name),
+                    how,
+                    Escaped(&room)
+                );
+                if session == self.session_id {
+                    self.rooms.remove(&room);
+                    if self.room.as_ref() == Some(&room) {
+                        self.room = self.rooms.iter().next().cloned();
+                    }
+                }
+            }
+            Ok(codec::ChatResponse::Muted {
+                room,
+                session,
+                name,
+                muted,
+            }) => {
+                let how = if muted { "muted" } else { "unmuted" };
+                println!(
+                    "!!! {} was {} in {}",
+                    who(session, name),
+                    how,
+                    Escaped(&room)
+                );
+            }
+            Ok(codec::ChatResponse::Invited {
+                room,
+                session,
+                name,
+            }) => println!(
+                "!!! {} invited you to {}",
+                who(session, name),
+                Escaped(&room)
+            ),
+            Ok(codec::ChatResponse::TopicChanged {
+                room,
+                session,
+                name,
+                topic,
+            }) => match topic {
+                Some(topic) => println!(
+                    "!!! {} set the topic of {} to: {}",
+                    who(session, name),
+                    Escaped(&room),
+                    Escaped(&topic)
+                ),
+                None => println!(
+                    "!!! {} cleared the topic of {}",
+                    who(session, name),
+                    Escaped(&room)
+                ),
+            },
+            Ok(codec::ChatResponse::Members { room, members, .. }) => {
+                println!("\n!!! Members of {}.", Escaped(&room));
+                for member in members {
+                    println!("{}", member);
+                }
+                println!();
             }
-            Ok(codec::ChatResponse::Rooms(rooms)) => {
+            Ok(codec::ChatResponse::RoomCreated { room }) => {
+                println!("!!! room created: {}", Escaped(&room));
+            }
+            Ok(codec::ChatResponse::RoomRemoved { room }) => {
+                println!("!!! room removed: {}", Escaped(&room));
+            }
+            Ok(codec::ChatResponse::History { messages, .. }) => {
+                for msg in messages {
+                    println!("{}", msg);
+                }
+            }
+            Ok(codec::ChatResponse::Rooms { rooms, .. }) => {
                 println!("\n!!! Available rooms.");
                 for room in rooms {
                     println!("{}", room);
                 }
                 println!();
             }
-            _ => ctx.stop(),
+            Ok(codec::ChatResponse::Error { code, message, .. }) => {
+                println!("!!! error {:?}: {}", code, Escaped(&message));
+            }
+            Ok(codec::ChatResponse::Ping) | Ok(codec::ChatResponse::Ack { .. }) => {}
+            Err(e) if e.is_recoverable() => println!("!!! bad frame from server: {}", e),
+            Err(_) => ctx.stop(),
         }
     }
 }
\ No newline at end of file
@@ -1,118 +1,901 @@
-#![allow(dead_code)]
-
-use std::io;
+use std::cell::Cell;
+use std::io::{Read, Write};
+use std::marker::PhantomData;
+use std::rc::Rc;
+use std::str::FromStr;
+use std::{fmt, io};
 
 use actix::prelude::*;
 use actix_codec::{Decoder, Encoder};
 use byteorder::{BigEndian, ByteOrder};
 use bytes::{BufMut, BytesMut};
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 use serde_json as json;
 
+/// Protocol version spoken by this build
+///
+/// Version 1 is the plain `Hello`/`Welcome` handshake on frames with a `u16`
+/// length prefix. Version 2 changes the wire format in ways version 1 peers
+/// can't follow:
+///
+/// - frames have a `u32` length prefix, its top bit flags a deflate compressed
+///   payload
+/// - payload format and compression are negotiated by `Hello` and `Welcome`
+/// - requests carry an optional id, echoed by the response to them
+/// - refused requests are answered with `Error` instead of a disconnect
+/// - `Message` names its room and is answered with `Sent`, messages arrive as
+///   `ChatMessage` envelopes
+/// - `Rooms` lists `RoomInfo` instead of bare names
+/// - requests and events for history, direct messages, names, accounts,
+///   moderation, room settings and editing messages
+pub const PROTOCOL_VERSION: u16 = 2;
+/// Oldest protocol version this build still accepts
+pub const MIN_PROTOCOL_VERSION: u16 = 2;
+/// Size of the frame length prefix in bytes
+const LENGTH_SIZE: usize = 4;
+/// Largest frame payload accepted unless configured otherwise
+pub const DEFAULT_MAX_FRAME_SIZE: usize = 1024 * 1024;
+/// Length prefix bit marking a compressed payload
+const COMPRESSED_FLAG: u32 = 1 << 31;
+/// Payloads up to this many bytes are sent uncompressed
+pub const COMPRESSION_THRESHOLD: usize = 1024;
+
 /// Client request
-#[derive(Serialize, Deserialize, Debug, Message)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Message)]
 #[rtype(result = "()")]
 #[serde(tag = "cmd", content = "data")]
 pub enum ChatRequest {
+    /// Handshake, has to be the first request on a connection
+    Hello {
+        version: u16,
+        client_name: String,
+        capabilities: Vec<String>,
+    },
     /// list rooms
     List,
-    /// Join rooms
-    Join(String),
-    /// Send message
-    Message(String),
+    /// Join room, creating it if it doesn't exist.
+    ///
+    /// `password` is needed for rooms protected by one, and becomes the
+    /// password of a room this request creates. `visibility` only applies to a
+    /// created room, it is public if `None`.
+    Join {
+        room: String,
+        #[serde(default)]
+        password: Option<String>,
+        #[serde(default)]
+        visibility: Option<Visibility>,
+    },
+    /// Leave room
+    Leave(String),
+    /// Send message to a joined room
+    Message { room: String, body: String },
+    /// Replace the body of message `id`, author only
+    EditMessage { id: u64, body: String },
+    /// Delete message `id`, its author and room moderators only
+    DeleteMessage { id: u64 },
+    /// Send private message to the user with name `to`, or to session `#id`
+    DirectMessage { to: String, body: String },
+    /// Pick a nickname, it has to be unique on the server
+    SetName(String),
+    /// Create an account and log in to it
+    Register { user: String, password: String },
+    /// Log in to an account, session goes by the account name from then on
+    Login { user: String, password: String },
+    /// Remove a user from a room, room owner and moderators only
+    Kick { room: String, user: String },
+    /// Remove a user from a room and keep them from joining again
+    Ban { room: String, user: String },
+    /// Let a banned user join the room again
+    Unban { room: String, user: String },
+    /// Keep a user from posting to a room
+    Mute { room: String, user: String },
+    /// Let a muted user post to the room again
+    Unmute { room: String, user: String },
+    /// Let a user join a room, room owner and moderators only. Invited users
+    /// don't need the room password.
+    Invite { room: String, user: String },
+    /// Change who can find and join a room, room owner only
+    SetVisibility {
+        room: String,
+        visibility: Visibility,
+    },
+    /// Protect a room with a password, or remove it with `None`, room owner only
+    SetPassword {
+        room: String,
+        password: Option<String>,
+    },
+    /// Change the topic of a room, or clear it with `None`, room owner and
+    /// moderators only
+    SetTopic { room: String, topic: Option<String> },
+    /// Change what a room is for, or clear it with `None`, room owner only
+    SetDescription {
+        room: String,
+        description: Option<String>,
+    },
+    /// Make a user moderator of a room, room owner only
+    AddModerator { room: String, user: String },
+    /// Take moderator role of a room away from a user, room owner only
+    RemoveModerator { room: String, user: String },
+    /// List members of a room
+    Who(String),
     /// Ping
     Ping,
+    /// Recent messages of a room, up to `limit` messages older than message
+    /// `before`, or the latest ones if `before` is `None`
+    History {
+        room: String,
+        before: Option<u64>,
+        limit: usize,
+    },
+}
+
+/// `ChatRequest` as sent on the wire, with optional client-chosen correlation id
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Request {
+    /// Echoed back as `request_id` in the reply to this request
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<u64>,
+    #[serde(flatten)]
+    pub body: ChatRequest,
+}
+
+impl From<ChatRequest> for Request {
+    fn from(body: ChatRequest) -> Self {
+        Request { id: None, body }
+    }
 }
 
 /// Server response
-#[derive(Serialize, Deserialize, Debug, Message)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Message)]
 #[rtype(result = "()")]
 #[serde(tag = "cmd", content = "data")]
 pub enum ChatResponse {
     Ping,
 
-    /// List of rooms
-    Rooms(Vec<String>),
+    /// Handshake accepted, carries negotiated protocol version.
+    ///
+    /// Frames after `Welcome` are encoded in `format` and compressed with
+    /// `compression` in both directions.
+    Welcome {
+        version: u16,
+        session_id: usize,
+        server_capabilities: Vec<String>,
+        #[serde(default)]
+        format: Format,
+        #[serde(default)]
+        compression: Compression,
+    },
+
+    /// List of rooms, sorted by name
+    Rooms {
+        rooms: Vec<RoomInfo>,
+        request_id: Option<u64>,
+    },
 
     /// Joined
-    Joined(String),
+    Joined {
+        room: String,
+        request_id: Option<u64>,
+    },
+
+    /// Left
+    Left {
+        room: String,
+        request_id: Option<u64>,
+    },
+
+    /// Request without a dedicated reply was accepted
+    Ack {
+        request_id: Option<u64>,
+    },
+
+    /// Message was accepted under `id`, sent only when client wants to
+    /// correlate
+    Sent {
+        id: u64,
+        request_id: Option<u64>,
+    },
 
     /// Message
-    Message(String),
+    Message(ChatMessage),
+
+    /// Private message to this session
+    DirectMessage(DirectMessage),
+
+    /// Message was edited, carries it as it reads now. Sent to the members of
+    /// its room.
+    MessageEdited(ChatMessage),
+
+    /// Message `id` was deleted by `session`, sent to the members of its room
+    MessageDeleted {
+        room: String,
+        id: u64,
+        session: usize,
+        name: Option<String>,
+    },
+
+    /// Session registered or logged in
+    LoggedIn {
+        user: String,
+        request_id: Option<u64>,
+    },
+
+    /// Session joined a room, sent to the other members of the room
+    UserJoined {
+        room: String,
+        session: usize,
+        name: Option<String>,
+    },
+
+    /// Session left a room, sent to the remaining members of the room
+    UserLeft {
+        room: String,
+        session: usize,
+        name: Option<String>,
+    },
+
+    /// Session went away, sent to the members of every room it was in
+    UserDisconnected {
+        room: String,
+        session: usize,
+        name: Option<String>,
+    },
+
+    /// Session picked a new nickname, sent to everyone sharing a room with it
+    /// and to the session itself
+    Renamed {
+        session: usize,
+        old_name: Option<String>,
+        new_name: String,
+    },
+
+    /// Session was removed from a room by a moderator, sent to the members of
+    /// the room and the removed session. `banned` if it can't join again.
+    Kicked {
+        room: String,
+        session: usize,
+        name: Option<String>,
+        banned: bool,
+    },
+
+    /// Session was muted or unmuted in a room by a moderator, sent to the
+    /// members of the room and the session itself
+    Muted {
+        room: String,
+        session: usize,
+        name: Option<String>,
+        muted: bool,
+    },
+
+    /// Session was invited to a room by `session`, sent to the invited session
+    Invited {
+        room: String,
+        session: usize,
+        name: Option<String>,
+    },
+
+    /// Topic of a room was changed by `session`, sent to the members of the room
+    TopicChanged {
+        room: String,
+        session: usize,
+        name: Option<String>,
+        topic: Option<String>,
+    },
+
+    /// Room was created or became public, sent to every session that can see
+    /// it in the room list
+    RoomCreated {
+        room: String,
+    },
+
+    /// Empty room was removed or got hidden, sent to every session that could
+    /// see it in the room list
+    RoomRemoved {
+        room: String,
+    },
+
+    /// Members of a room
+    Members {
+        room: String,
+        members: Vec<Member>,
+        request_id: Option<u64>,
+    },
+
+    /// Batch of room history, oldest message first
+    History {
+        room: String,
+        messages: Vec<ChatMessage>,
+        request_id: Option<u64>,
+    },
+
+    /// Request could not be served, connection stays open
+    Error {
+        code: ErrorCode,
+        message: String,
+        request_id: Option<u64>,
+    },
 }
 
-/// Codec for Client -> Server transport
-pub struct ChatCodec;
+/// Chat message as delivered to peers
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ChatMessage {
+    /// Server assigned message id
+    pub id: u64,
+    /// Room message was sent to
+    pub room: String,
+    /// Session id of sender, `None` for server notices
+    pub sender: Option<usize>,
+    /// Display name of sender, if it picked one
+    pub sender_name: Option<String>,
+    /// Account sender was logged in to, lower case, `None` for guests
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sender_account: Option<String>,
+    /// Server time, milliseconds since unix epoch
+    pub timestamp: u64,
+    pub body: String,
+    /// Server time of the latest edit, `None` if message was never edited
+    #[serde(default)]
+    pub edited: Option<u64>,
+}
 
-impl Decoder for ChatCodec {
-    type Item = ChatRequest;
-    type Error = io::Error;
-    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
-        let size = {
-            if src.len() < 2 {
-                return Ok(None);
+/// Renders as `[hh:mm:ss] [room] [id] sender: body`, time in UTC, with
+/// ` (edited)` after edited messages
+impl fmt::Display for ChatMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write_time(f, self.timestamp)?;
+        write!(f, " [{}] [{}] ", Escaped(&self.room), self.id)?;
+        let body = Escaped(&self.body);
+        match (&self.sender_name, self.sender) {
+            (Some(name), _) => write!(f, "{}: {}", Escaped(name), body)?,
+            (None, Some(id)) => write!(f, "#{}: {}", id, body)?,
+            (None, None) => write!(f, "{}", body)?,
+        }
+        if self.edited.is_some() {
+            write!(f, " (edited)")?;
+        }
+        Ok(())
+    }
+}
+
+/// Private message as delivered to its recipient
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct DirectMessage {
+    /// Server assigned message id, shared with room messages
+    pub id: u64,
+    /// Session id of sender
+    pub sender: usize,
+    /// Display name of sender, if it picked one
+    pub sender_name: Option<String>,
+    /// Server time, milliseconds since unix epoch
+    pub timestamp: u64,
+    pub body: String,
+}
+
+/// Renders as `[hh:mm:ss] [private] sender: body`, time in UTC
+impl fmt::Display for DirectMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write_time(f, self.timestamp)?;
+        let body = Escaped(&self.body);
+        match &self.sender_name {
+            Some(name) => write!(f, " [private] {}: {}", Escaped(name), body),
+            None => write!(f, " [private] #{}: {}", self.sender, body),
+        }
+    }
+}
+
+/// Session in a room, as listed by `Who`
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Member {
+    /// Session id
+    pub session: usize,
+    /// Nickname, if session picked one
+    pub name: Option<String>,
+    /// How session is connected
+    pub transport: Transport,
+}
+
+/// Renders as `name (transport)`, `#id (transport)` without a name
+impl fmt::Display for Member {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.name {
+            Some(name) => write!(f, "{} ({})", Escaped(name), self.transport),
+            None => write!(f, "#{} ({})", self.session, self.transport),
+        }
+    }
+}
+
+/// Room as listed by `List`
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct RoomInfo {
+    /// Room name
+    pub name: String,
+    /// What is going on in the room right now, set by moderators
+    pub topic: Option<String>,
+    /// What the room is for, set by its owner
+    pub description: Option<String>,
+    /// Server time room was created, milliseconds since unix epoch
+    pub created: u64,
+    /// Number of sessions in the room
+    pub members: usize,
+    /// Server time of the latest message, creation time if there is none
+    pub last_activity: u64,
+}
+
+/// Renders as `name (n members): topic - description`, leaving out topic and
+/// description the room doesn't have
+impl fmt::Display for RoomInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ({} member", Escaped(&self.name), self.members)?;
+        if self.members != 1 {
+            write!(f, "s")?;
+        }
+        write!(f, ")")?;
+        if let Some(topic) = &self.topic {
+            write!(f, ": {}", Escaped(topic))?;
+        }
+        if let Some(description) = &self.description {
+            write!(f, " - {}", Escaped(description))?;
+        }
+        Ok(())
+    }
+}
+
+/// Kind of connection a session came in through
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum Transport {
+    /// Framed protocol of this module
+    Tcp,
+    /// Web page
+    WebSocket,
+    /// Line oriented plain text
+    Text,
+}
+
+impl fmt::Display for Transport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Transport::Tcp => write!(f, "tcp"),
+            Transport::WebSocket => write!(f, "websocket"),
+            Transport::Text => write!(f, "text"),
+        }
+    }
+}
+
+/// Who can find and join a room
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+pub enum Visibility {
+    /// Listed, anyone can join
+    #[default]
+    Public,
+    /// Not listed to non-members, anyone who knows the name can join
+    Unlisted,
+    /// Not listed to non-members, only invited users can join
+    InviteOnly,
+}
+
+impl fmt::Display for Visibility {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Visibility::Public => write!(f, "public"),
+            Visibility::Unlisted => write!(f, "unlisted"),
+            Visibility::InviteOnly => write!(f, "invite-only"),
+        }
+    }
+}
+
+/// Parses what `Display` renders
+impl FromStr for Visibility {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "public" => Ok(Visibility::Public),
+            "unlisted" => Ok(Visibility::Unlisted),
+            "invite-only" => Ok(Visibility::InviteOnly),
+            _ => Err(format!("unknown visibility: {}", s)),
+        }
+    }
+}
+
+/// Text from peers, rendered on one line. Control characters are escaped, so
+/// nobody can break a line and pass the rest off as a line of its own.
+pub struct Escaped<'a>(pub &'a str);
+
+impl fmt::Display for Escaped<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for c in self.0.chars() {
+            if c.is_control() {
+                write!(f, "{}", c.escape_default())?;
+            } else {
+                write!(f, "{}", c)?;
             }
-            BigEndian::read_u16(src.as_ref()) as usize
-        };
+        }
+        Ok(())
+    }
+}
+
+/// `[hh:mm:ss]` of a timestamp in milliseconds since unix epoch, in UTC
+fn write_time(f: &mut fmt::Formatter<'_>, timestamp: u64) -> fmt::Result {
+    let secs = timestamp / 1000;
+    write!(
+        f,
+        "[{:02}:{:02}:{:02}]",
+        secs / 3600 % 24,
+        secs / 60 % 60,
+        secs % 60
+    )
+}
+
+/// Reason reported to peer in `ChatResponse::Error`
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum ErrorCode {
+    /// Frame payload is not a valid request
+    MalformedRequest,
+    /// Request names a command server doesn't know
+    UnknownCommand,
+    /// Frame is larger than the maximum frame size
+    FrameTooLarge,
+    /// Request needs membership in a room
+    NotInRoom,
+    /// Request was sent before `Hello`
+    HandshakeRequired,
+    /// Peer protocol version is not supported
+    UnsupportedVersion,
+    /// Request is not valid in current session state
+    InvalidRequest,
+    /// Server failed to handle request
+    Internal,
+    /// Request names a room that doesn't exist
+    NoSuchRoom,
+    /// Request names a user or session that isn't connected
+    NoSuchUser,
+    /// Nickname is empty, too long or has characters not allowed in names
+    InvalidName,
+    /// Nickname is used by another session
+    NameTaken,
+    /// Unknown user or wrong password
+    InvalidCredentials,
+    /// Request needs a role in the room the session doesn't have
+    PermissionDenied,
+    /// Session is banned from the room
+    Banned,
+    /// Session is muted in the room
+    Muted,
+    /// Room is invite-only and session wasn't invited
+    NotInvited,
+    /// Session sends too fast, its message was dropped or it is being
+    /// disconnected, or it failed to log in too often and has to wait
+    RateLimited,
+    /// Request names a message that doesn't exist or fell out of history
+    NoSuchMessage,
+}
 
-        if src.len() >= size + 2 {
-            let _ = src.split_to(2);
-            let buf = src.split_to(size);
-            Ok(Some(json::from_slice::<ChatRequest>(&buf)?))
+/// Errors produced while decoding frames
+#[derive(Debug)]
+pub enum CodecError {
+    /// Transport failed, connection is unusable
+    Io(io::Error),
+    /// Frame exceeded maximum frame size, its payload is skipped
+    FrameTooLarge { size: usize, max: usize },
+    /// Frame payload can't be deserialized or doesn't match any request
+    Malformed(String),
+    /// Frame payload names an unknown command
+    UnknownCommand(String),
+}
+
+impl CodecError {
+    /// Whether the stream is still in sync and decoding can go on
+    pub fn is_recoverable(&self) -> bool {
+        !matches!(self, CodecError::Io(_))
+    }
+
+    /// Error code reported to peer
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            CodecError::Io(_) => ErrorCode::Internal,
+            CodecError::FrameTooLarge { .. } => ErrorCode::FrameTooLarge,
+            CodecError::Malformed(_) => ErrorCode::MalformedRequest,
+            CodecError::UnknownCommand(_) => ErrorCode::UnknownCommand,
+        }
+    }
+}
+
+impl fmt::Display for CodecError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CodecError::Io(e) => write!(f, "io error: {}", e),
+            CodecError::FrameTooLarge { size, max } => write!(
+                f,
+                "frame of {} bytes exceeds maximum frame size of {} bytes",
+                size, max
+            ),
+            CodecError::Malformed(e) => write!(f, "malformed frame: {}", e),
+            CodecError::UnknownCommand(e) => write!(f, "unknown command: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for CodecError {}
+
+impl From<io::Error> for CodecError {
+    fn from(e: io::Error) -> Self {
+        CodecError::Io(e)
+    }
+}
+
+impl CodecError {
+    fn from_deserialize(e: impl fmt::Display) -> CodecError {
+        // serde has no dedicated error for unknown enum tags, tell them apart by message
+        let message = e.to_string();
+        if message.starts_with("unknown variant") {
+            CodecError::UnknownCommand(message)
         } else {
-            Ok(None)
+            CodecError::Malformed(message)
         }
     }
 }
 
+impl From<CodecError> for io::Error {
+    fn from(e: CodecError) -> Self {
+        match e {
+            CodecError::Io(e) => e,
+            e => io::Error::new(io::ErrorKind::InvalidData, e),
+        }
+    }
+}
 
-impl Encoder<ChatResponse> for ChatCodec {
-    type Error = io::Error;
-    fn encode(&mut self, msg: ChatResponse, dst: &mut BytesMut) -> Result<(), Self::Error> {
-        let msg = json::to_string(&msg).unwrap();
-        let msg_ref: &[u8] = msg.as_ref();
+/// Serialization format of frame payloads
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+pub enum Format {
+    /// Human readable, used for handshake and by default
+    #[default]
+    Json,
+    /// Compact binary encoding
+    MessagePack,
+    /// Compact binary encoding
+    Cbor,
+}
 
-        dst.reserve(msg_ref.len() + 2);
-        dst.put_u16(msg_ref.len() as u16);
-        dst.put(msg_ref);
-        Ok(())
+impl Format {
+    /// Handshake capability requesting this format
+    pub fn capability(self) -> &'static str {
+        match self {
+            Format::Json => "format:json",
+            Format::MessagePack => "format:msgpack",
+            Format::Cbor => "format:cbor",
+        }
+    }
+
+    /// Format requested by handshake capability `cap`, if any
+    pub fn from_capability(cap: &str) -> Option<Format> {
+        match cap {
+            "format:json" => Some(Format::Json),
+            "format:msgpack" => Some(Format::MessagePack),
+            "format:cbor" => Some(Format::Cbor),
+            _ => None,
+        }
+    }
+
+    fn serialize<T: Serialize>(self, msg: &T) -> Result<Vec<u8>, io::Error> {
+        let invalid = |e: String| io::Error::new(io::ErrorKind::InvalidData, e);
+        match self {
+            Format::Json => json::to_vec(msg).map_err(|e| invalid(e.to_string())),
+            // structs are written as maps, flattened `Request` fields need names
+            Format::MessagePack => rmp_serde::to_vec_named(msg).map_err(|e| invalid(e.to_string())),
+            Format::Cbor => serde_cbor::to_vec(msg).map_err(|e| invalid(e.to_string())),
+        }
+    }
+
+    fn deserialize<T: DeserializeOwned>(self, buf: &[u8]) -> Result<T, CodecError> {
+        match self {
+            Format::Json => json::from_slice(buf).map_err(CodecError::from_deserialize),
+            Format::MessagePack => rmp_serde::from_slice(buf).map_err(CodecError::from_deserialize),
+            Format::Cbor => serde_cbor::from_slice(buf).map_err(CodecError::from_deserialize),
+        }
     }
 }
 
-/// Codec for server -> Client transport
-pub struct ClientChatCodec;
+/// Compression of frame payloads
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+pub enum Compression {
+    #[default]
+    None,
+    Deflate,
+}
 
-impl Decoder for ClientChatCodec {
-    type Item = ChatResponse;
-    type Error = io::Error;
-    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
-        let size = {
-            if src.len() < 2 {
+impl Compression {
+    /// Handshake capability requesting this compression
+    pub fn capability(self) -> &'static str {
+        match self {
+            Compression::None => "compress:none",
+            Compression::Deflate => "compress:deflate",
+        }
+    }
+
+    /// Compression requested by handshake capability `cap`, if any
+    pub fn from_capability(cap: &str) -> Option<Compression> {
+        match cap {
+            "compress:none" => Some(Compression::None),
+            "compress:deflate" => Some(Compression::Deflate),
+            _ => None,
+        }
+    }
+
+    fn compress(self, buf: &[u8]) -> Result<Vec<u8>, io::Error> {
+        let mut encoder = DeflateEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(buf)?;
+        encoder.finish()
+    }
+
+    /// Inflate `buf`, refusing to produce more than `max` bytes
+    fn decompress(self, buf: &[u8], max: usize) -> Result<Vec<u8>, CodecError> {
+        if self == Compression::None {
+            return Err(CodecError::Malformed(
+                "compressed frame, but compression was not negotiated".to_owned(),
+            ));
+        }
+        let mut out = Vec::new();
+        DeflateDecoder::new(buf)
+            .take(max as u64 + 1)
+            .read_to_end(&mut out)
+            .map_err(|e| CodecError::Malformed(e.to_string()))?;
+        check_frame_size(out.len(), max)?;
+        Ok(out)
+    }
+}
+
+/// How frame payloads of a connection are encoded
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Encoding {
+    pub format: Format,
+    pub compression: Compression,
+}
+
+/// Encoding shared by read and write half of a connection.
+///
+/// Both halves switch together once handshake settled the encoding.
+#[derive(Clone, Debug, Default)]
+pub struct SharedEncoding(Rc<Cell<Encoding>>);
+
+impl SharedEncoding {
+    pub fn get(&self) -> Encoding {
+        self.0.get()
+    }
+
+    pub fn set(&self, encoding: Encoding) {
+        self.0.set(encoding)
+    }
+}
+
+/// Length-delimited codec decoding `In` frames and encoding `Out` frames.
+///
+/// Every frame is a big endian `u32` payload length followed by the payload
+/// serialized in the connection's `Format`. Highest bit of the length marks
+/// payloads compressed with the negotiated `Compression`, only payloads
+/// larger than `COMPRESSION_THRESHOLD` get compressed.
+pub struct FramedCodec<In, Out> {
+    max_frame_size: usize,
+    encoding: SharedEncoding,
+    /// bytes of an oversized frame still to be skipped
+    discard: usize,
+    _types: PhantomData<fn(Out) -> In>,
+}
+
+/// Codec for Client -> Server transport, used by server
+pub type ChatCodec = FramedCodec<Request, ChatResponse>;
+
+/// Codec for server -> Client transport, used by clients
+pub type ClientChatCodec = FramedCodec<ChatResponse, Request>;
+
+impl<In, Out> FramedCodec<In, Out> {
+    /// Codec rejecting frames with payload larger than `max_frame_size` bytes
+    pub fn new(max_frame_size: usize, encoding: SharedEncoding) -> Self {
+        assert!(
+            max_frame_size < COMPRESSED_FLAG as usize,
+            "max frame size has to fit in 31 bits"
+        );
+        FramedCodec {
+            max_frame_size,
+            encoding,
+            discard: 0,
+            _types: PhantomData,
+        }
+    }
+
+    /// Split next complete frame payload off `src`, along with its compressed flag.
+    ///
+    /// Frames over the limit are refused before anything gets allocated for
+    /// them, their payload is skipped so the stream stays in sync.
+    fn read_frame(&mut self, src: &mut BytesMut) -> Result<Option<(bool, BytesMut)>, CodecError> {
+        if self.discard > 0 {
+            let skip = self.discard.min(src.len());
+            let _ = src.split_to(skip);
+            self.discard -= skip;
+            if self.discard > 0 {
                 return Ok(None);
             }
-            BigEndian::read_u16(src.as_ref()) as usize
+        }
+
+        let (compressed, size) = {
+            if src.len() < LENGTH_SIZE {
+                return Ok(None);
+            }
+            let length = BigEndian::read_u32(src.as_ref());
+            (
+                length & COMPRESSED_FLAG != 0,
+                (length & !COMPRESSED_FLAG) as usize,
+            )
         };
-        if src.len() >= size + 2 {
-            let _ = src.split_to(2);
-            let buf = src.split_to(size);
-            Ok(Some(json::from_slice::<ChatResponse>(&buf)?))
+        if let Err(e) = check_frame_size(size, self.max_frame_size) {
+            let _ = src.split_to(LENGTH_SIZE);
+            self.discard = size;
+            return Err(e);
+        }
+
+        if src.len() >= size + LENGTH_SIZE {
+            let _ = src.split_to(LENGTH_SIZE);
+            Ok(Some((compressed, src.split_to(size))))
         } else {
+            // partial frame, wait for the rest
+            src.reserve(size + LENGTH_SIZE - src.len());
             Ok(None)
         }
     }
 }
 
-impl Encoder<ChatRequest> for ClientChatCodec {
+impl<In, Out> Default for FramedCodec<In, Out> {
+    fn default() -> Self {
+        FramedCodec::new(DEFAULT_MAX_FRAME_SIZE, SharedEncoding::default())
+    }
+}
+
+impl<In: DeserializeOwned, Out> Decoder for FramedCodec<In, Out> {
+    type Item = In;
+    type Error = CodecError;
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        let encoding = self.encoding.get();
+        match self.read_frame(src)? {
+            Some((true, buf)) => {
+                let buf = encoding.compression.decompress(&buf, self.max_frame_size)?;
+                Ok(Some(encoding.format.deserialize(&buf)?))
+            }
+            Some((false, buf)) => Ok(Some(encoding.format.deserialize(&buf)?)),
+            None => Ok(None),
+        }
+    }
+}
+
+impl<In, Out: Serialize> Encoder<Out> for FramedCodec<In, Out> {
     type Error = io::Error;
+    fn encode(&mut self, msg: Out, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        let encoding = self.encoding.get();
+        let mut msg = encoding.format.serialize(&msg)?;
+        // uncompressed size is checked, peer refuses to inflate beyond it
+        check_frame_size(msg.len(), self.max_frame_size)?;
 
-    fn encode(&mut self, msg: ChatRequest, dst: &mut BytesMut) -> Result<(), Self::Error> {
-        let msg = json::to_string(&msg).unwrap();
+        let mut flag = 0;
+        if encoding.compression != Compression::None && msg.len() > COMPRESSION_THRESHOLD {
+            let compressed = encoding.compression.compress(&msg)?;
+            if compressed.len() < msg.len() {
+                msg = compressed;
+                flag = COMPRESSED_FLAG;
+            }
+        }
         let msg_ref: &[u8] = msg.as_ref();
 
-        dst.reserve(msg_ref.len() + 2);
-        dst.put_u16(msg_ref.len() as u16);
+        dst.reserve(msg_ref.len() + LENGTH_SIZE);
+        dst.put_u32(msg_ref.len() as u32 | flag);
         dst.put(msg_ref);
-
         Ok(())
     }
 }
+
+fn check_frame_size(size: usize, max: usize) -> Result<(), CodecError> {
+    if size > max {
+        return Err(CodecError::FrameTooLarge { size, max });
+    }
+    Ok(())
+}
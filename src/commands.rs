@@ -0,0 +1,243 @@
+//! Slash commands of the plain text and websocket sessions.
+//!
+//! Both sessions take lines typed by people: a line starting with `/` is a
+//! command, anything else is a message for the current room. Parsing is
+//! shared here, what a command does is up to the session.
+
+use std::fmt;
+
+use tcp_chat::codec::Visibility;
+
+use crate::server::Moderation;
+
+/// A line typed by the peer
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    /// `/list`
+    List,
+    /// `/join <room> [password]`
+    Join {
+        room: String,
+        password: Option<String>,
+    },
+    /// `/create <room> <visibility> [password]`
+    Create {
+        room: String,
+        visibility: Visibility,
+        password: Option<String>,
+    },
+    /// `/leave [room]`, current room if none
+    Leave(Option<String>),
+    /// `/room <room>`, switch current room
+    Room(String),
+    /// `/name <name>`
+    Name(String),
+    /// `/msg <name> <message>`
+    Direct { to: String, body: String },
+    /// `/register <user> <password>`
+    Register { user: String, password: String },
+    /// `/login <user> <password>`
+    Login { user: String, password: String },
+    /// `/kick`, `/ban`, `/unban`, `/mute`, `/unmute`, `/invite`, `/mod` or
+    /// `/unmod <user>` in the current room
+    Moderate { action: Moderation, user: String },
+    /// `/visibility <visibility>` of the current room
+    Visibility(Visibility),
+    /// `/password [password]` of the current room, none opens it again
+    Password(Option<String>),
+    /// `/topic [topic]` of the current room, none clears it
+    Topic(Option<String>),
+    /// `/describe [description]` of the current room, none clears it
+    Describe(Option<String>),
+    /// `/edit <message>`, replaces the latest message sent
+    Edit(String),
+    /// `/delete [id]`, latest message sent if none
+    Delete(Option<u64>),
+    /// `/who [room]`, current room if none
+    Who(Option<String>),
+    /// `/history [id]`, page of the current room before message `id`
+    History(Option<u64>),
+    /// Anything not starting with `/`, a message for the current room
+    Message(String),
+}
+
+/// Why a line is not a command
+#[derive(Debug, PartialEq)]
+pub enum ParseError {
+    /// Arguments are missing, says which
+    Missing(&'static str),
+    /// Message id is not a number
+    BadId,
+    /// Visibility is none of `public`, `unlisted` or `invite-only`
+    BadVisibility(String),
+    /// No such command
+    Unknown(String),
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::Missing(what) => write!(f, "{} required", what),
+            ParseError::BadId => write!(f, "message id must be a number"),
+            ParseError::BadVisibility(e) => write!(f, "{}", e),
+            ParseError::Unknown(line) => write!(f, "unknown command: {:?}", line),
+        }
+    }
+}
+
+/// Parse a trimmed line from the peer
+pub fn parse(line: &str) -> Result<Command, ParseError> {
+    if !line.starts_with('/') {
+        return Ok(Command::Message(line.to_owned()));
+    }
+
+    let (command, args) = match line.split_once(' ') {
+        Some((command, args)) => (command, Some(args.trim()).filter(|a| !a.is_empty())),
+        None => (line, None),
+    };
+    // two arguments, rest of the line is the second
+    let pair = |missing| {
+        args.and_then(|a| a.split_once(' '))
+            .map(|(first, rest)| (first.to_owned(), rest.trim().to_owned()))
+            .ok_or(ParseError::Missing(missing))
+    };
+    let required = |missing| args.map(str::to_owned).ok_or(ParseError::Missing(missing));
+    let id = || {
+        args.map(|id| id.parse().map_err(|_| ParseError::BadId))
+            .transpose()
+    };
+
+    let command = match command {
+        "/list" => Command::List,
+        // password, if any, is the rest of the line
+        "/join" => match pair("room name is") {
+            Ok((room, password)) => Command::Join {
+                room,
+                password: Some(password),
+            },
+            Err(_) => Command::Join {
+                room: required("room name is")?,
+                password: None,
+            },
+        },
+        "/create" => {
+            let args: Vec<&str> = args.map_or(Vec::new(), |a| a.splitn(3, ' ').collect());
+            let visibility = match args.get(1) {
+                Some(visibility) => visibility.parse().map_err(ParseError::BadVisibility)?,
+                None => return Err(ParseError::Missing("room name and visibility are")),
+            };
+            Command::Create {
+                room: args[0].to_owned(),
+                visibility,
+                password: args.get(2).map(|p| p.trim().to_owned()),
+            }
+        }
+        "/leave" => Command::Leave(args.map(str::to_owned)),
+        "/room" => Command::Room(required("room name is")?),
+        "/name" => Command::Name(required("name is")?),
+        "/msg" => {
+            let (to, body) = pair("name and message are")?;
+            Command::Direct { to, body }
+        }
+        "/register" => {
+            let (user, password) = pair("user name and password are")?;
+            Command::Register { user, password }
+        }
+        "/login" => {
+            let (user, password) = pair("user name and password are")?;
+            Command::Login { user, password }
+        }
+        "/kick" | "/ban" | "/unban" | "/mute" | "/unmute" | "/invite" | "/mod" | "/unmod" => {
+            let action = match command {
+                "/kick" => Moderation::Kick,
+                "/ban" => Moderation::Ban,
+                "/unban" => Moderation::Unban,
+                "/mute" => Moderation::Mute,
+                "/unmute" => Moderation::Unmute,
+                "/invite" => Moderation::Invite,
+                "/mod" => Moderation::AddModerator,
+                _ => Moderation::RemoveModerator,
+            };
+            Command::Moderate {
+                action,
+                user: required("user name is")?,
+            }
+        }
+        "/visibility" => Command::Visibility(
+            required("visibility is")?
+                .parse()
+                .map_err(ParseError::BadVisibility)?,
+        ),
+        "/password" => Command::Password(args.map(str::to_owned)),
+        "/topic" => Command::Topic(args.map(str::to_owned)),
+        "/describe" => Command::Describe(args.map(str::to_owned)),
+        "/edit" => Command::Edit(required("message is")?),
+        "/delete" => Command::Delete(id()?),
+        "/who" => Command::Who(args.map(str::to_owned)),
+        "/history" => Command::History(id()?),
+        _ => return Err(ParseError::Unknown(line.to_owned())),
+    };
+    Ok(command)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn plain_line_is_a_message() {
+        assert_eq!(parse("hello"), Ok(Command::Message("hello".to_owned())));
+    }
+
+    #[test]
+    fn rest_of_line_is_last_argument() {
+        assert_eq!(
+            parse("/join room  secret pass "),
+            Ok(Command::Join {
+                room: "room".to_owned(),
+                password: Some("secret pass".to_owned()),
+            })
+        );
+        assert_eq!(
+            parse("/msg bob hi there"),
+            Ok(Command::Direct {
+                to: "bob".to_owned(),
+                body: "hi there".to_owned(),
+            })
+        );
+        assert_eq!(
+            parse("/create room invite-only pass"),
+            Ok(Command::Create {
+                room: "room".to_owned(),
+                visibility: Visibility::InviteOnly,
+                password: Some("pass".to_owned()),
+            })
+        );
+    }
+
+    #[test]
+    fn optional_arguments() {
+        assert_eq!(parse("/topic"), Ok(Command::Topic(None)));
+        assert_eq!(parse("/topic  "), Ok(Command::Topic(None)));
+        assert_eq!(parse("/delete 42"), Ok(Command::Delete(Some(42))));
+        assert_eq!(parse("/history"), Ok(Command::History(None)));
+    }
+
+    #[test]
+    fn bad_arguments() {
+        assert_eq!(parse("/join"), Err(ParseError::Missing("room name is")));
+        assert_eq!(
+            parse("/login bob"),
+            Err(ParseError::Missing("user name and password are"))
+        );
+        assert_eq!(parse("/delete last"), Err(ParseError::BadId));
+        assert!(matches!(
+            parse("/create room secret"),
+            Err(ParseError::BadVisibility(_))
+        ));
+        assert_eq!(
+            parse("/frobnicate"),
+            Err(ParseError::Unknown("/frobnicate".to_owned()))
+        );
+    }
+}
@@ -0,0 +1,227 @@
+//! Flood protection of messages sessions send.
+//!
+//! Every session gets a token bucket for messages and one for message bytes.
+//! Sending while either bucket is empty earns the session a strike, and
+//! penalties escalate with the strikes: a warning, dropped messages, a
+//! temporary mute, and finally disconnect. Strikes are forgiven once the
+//! session behaved for a while.
+
+use std::time::{Duration, Instant};
+
+/// How fast sessions may send messages, and what happens when they don't
+/// keep to it
+#[derive(Clone, Debug)]
+pub struct RateLimit {
+    /// Messages a session may send per second, on average
+    pub messages_per_second: f64,
+    /// Message body bytes a session may send per second, on average
+    pub bytes_per_second: f64,
+    /// Seconds worth of messages and bytes a session may send in one go
+    pub burst: f64,
+    /// Strikes answered with a warning only, the message is still delivered
+    pub warnings: u32,
+    /// Strikes after the warnings whose message is dropped
+    pub drops: u32,
+    /// How long a session out of warnings and drops is muted. Session is
+    /// disconnected on the next strike after that.
+    pub mute: Duration,
+    /// Strikes are forgotten after this long without a new one
+    pub forgive: Duration,
+}
+
+impl Default for RateLimit {
+    fn default() -> Self {
+        RateLimit {
+            messages_per_second: 5.0,
+            bytes_per_second: 16.0 * 1024.0,
+            burst: 2.0,
+            warnings: 1,
+            drops: 3,
+            mute: Duration::from_secs(30),
+            forgive: Duration::from_secs(60),
+        }
+    }
+}
+
+/// What to do with a message, as decided by `Limiter::check`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Verdict {
+    /// Within limits
+    Allow,
+    /// Over the limit, deliver it but warn the session
+    Warn,
+    /// Over the limit, drop it
+    Drop,
+    /// Session is muted for flooding, drop it
+    Muted,
+    /// Session keeps flooding, disconnect it
+    Disconnect,
+}
+
+/// Tokens refilling at a steady rate, up to a capacity
+struct TokenBucket {
+    /// tokens added per second
+    rate: f64,
+    capacity: f64,
+    /// may go below zero, large messages leave the bucket in debt
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    /// Full bucket
+    fn new(rate: f64, burst: f64, now: Instant) -> TokenBucket {
+        let capacity = rate * burst;
+        TokenBucket {
+            rate,
+            capacity,
+            tokens: capacity,
+            updated: now,
+        }
+    }
+
+    /// Add tokens for the time passed since last refill
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity);
+        self.updated = now;
+    }
+}
+
+/// Rate limit state of one session
+pub struct Limiter {
+    messages: TokenBucket,
+    bytes: TokenBucket,
+    strikes: u32,
+    last_strike: Option<Instant>,
+    muted_until: Option<Instant>,
+}
+
+impl Limiter {
+    pub fn new(limit: &RateLimit) -> Limiter {
+        let now = Instant::now();
+        Limiter {
+            messages: TokenBucket::new(limit.messages_per_second, limit.burst, now),
+            bytes: TokenBucket::new(limit.bytes_per_second, limit.burst, now),
+            strikes: 0,
+            last_strike: None,
+            muted_until: None,
+        }
+    }
+
+    /// Charge the session for a message of `bytes` bytes sent at `now`.
+    ///
+    /// A message larger than the byte bucket goes through as long as the
+    /// bucket isn't empty, it has to refill before the next one.
+    pub fn check(&mut self, limit: &RateLimit, bytes: usize, now: Instant) -> Verdict {
+        if self.muted_until.is_some_and(|until| now < until) {
+            return Verdict::Muted;
+        }
+        self.messages.refill(now);
+        self.bytes.refill(now);
+        if self.messages.tokens >= 1.0 && self.bytes.tokens > 0.0 {
+            self.take(bytes);
+            return Verdict::Allow;
+        }
+
+        if let Some(last) = self.last_strike {
+            if now.saturating_duration_since(last) >= limit.forgive {
+                self.strikes = 0;
+            }
+        }
+        self.strikes += 1;
+        self.last_strike = Some(now);
+
+        if self.strikes <= limit.warnings {
+            // warned messages are delivered, so they are paid for too
+            self.take(bytes);
+            Verdict::Warn
+        } else if self.strikes <= limit.warnings + limit.drops {
+            Verdict::Drop
+        } else if self.strikes == limit.warnings + limit.drops + 1 {
+            self.muted_until = Some(now + limit.mute);
+            Verdict::Muted
+        } else {
+            Verdict::Disconnect
+        }
+    }
+
+    fn take(&mut self, bytes: usize) {
+        self.messages.tokens -= 1.0;
+        self.bytes.tokens -= bytes as f64;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// One message per second, no bursts
+    fn limit() -> RateLimit {
+        RateLimit {
+            messages_per_second: 1.0,
+            bytes_per_second: 1024.0 * 1024.0,
+            burst: 1.0,
+            warnings: 1,
+            drops: 2,
+            mute: Duration::from_secs(10),
+            forgive: Duration::from_secs(60),
+        }
+    }
+
+    #[test]
+    fn penalties_escalate() {
+        let limit = limit();
+        let mut limiter = Limiter::new(&limit);
+        let start = Instant::now();
+        let verdicts: Vec<Verdict> = (0..6).map(|_| limiter.check(&limit, 1, start)).collect();
+        assert_eq!(
+            verdicts,
+            [
+                Verdict::Allow,
+                Verdict::Warn,
+                Verdict::Drop,
+                Verdict::Drop,
+                Verdict::Muted,
+                // muted sessions don't collect strikes
+                Verdict::Muted,
+            ]
+        );
+
+        // mute is over, but the next strike comes too soon to be forgiven
+        let later = start + Duration::from_secs(11);
+        assert_eq!(limiter.check(&limit, 1, later), Verdict::Allow);
+        assert_eq!(limiter.check(&limit, 1, later), Verdict::Disconnect);
+    }
+
+    #[test]
+    fn strikes_are_forgiven() {
+        let limit = limit();
+        let mut limiter = Limiter::new(&limit);
+        let start = Instant::now();
+        assert_eq!(limiter.check(&limit, 1, start), Verdict::Allow);
+        assert_eq!(limiter.check(&limit, 1, start), Verdict::Warn);
+        assert_eq!(limiter.check(&limit, 1, start), Verdict::Drop);
+
+        let later = start + limit.forgive;
+        assert_eq!(limiter.check(&limit, 1, later), Verdict::Allow);
+        assert_eq!(limiter.check(&limit, 1, later), Verdict::Warn);
+    }
+
+    #[test]
+    fn large_message_leaves_byte_debt() {
+        let limit = RateLimit {
+            messages_per_second: 100.0,
+            bytes_per_second: 100.0,
+            ..limit()
+        };
+        let mut limiter = Limiter::new(&limit);
+        let start = Instant::now();
+        // ten seconds worth of bytes go through at once
+        assert_eq!(limiter.check(&limit, 1000, start), Verdict::Allow);
+        // and have to be paid off before the next message
+        let at = |secs| start + Duration::from_secs(secs);
+        assert_eq!(limiter.check(&limit, 1, at(8)), Verdict::Warn);
+        assert_eq!(limiter.check(&limit, 1, at(10)), Verdict::Allow);
+    }
+}
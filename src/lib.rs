@@ -0,0 +1,5 @@
+//! Wire protocol of the chat server's tcp port.
+//!
+//! Shared by server and client binaries, Rust clients can depend on it as well.
+
+pub mod codec;
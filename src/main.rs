@@ -1,18 +1,48 @@
+use std::collections::HashSet;
+use std::future::Future;
+use std::iter;
+use std::net::IpAddr;
 use std::time::{Duration, Instant};
 
 use actix::*;
 use actix_files as fs;
 use actix_web::{App, Error, HttpRequest, HttpResponse, HttpServer, web};
 use actix_web_actors::ws;
+use serde_json as json;
 
-mod codec;
+use tcp_chat::codec::{ChatResponse, Transport, Visibility};
+
+use crate::accounts::AccountError;
+use crate::commands::Command;
+
+mod accounts;
+mod commands;
+mod flood;
+mod rooms;
 mod server;
 mod session;
+mod storage;
+mod text_session;
 
 /// How often heartbeat pings are sent
 const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
 /// How long before lack of client response causes a timeout
 const CLIENT_TIMEOUT: Duration = Duration::from_secs(10);
+/// Largest frame payload accepted from tcp peers
+const MAX_FRAME_SIZE: usize = 1024 * 1024;
+/// Log file rooms, messages and accounts are kept in across restarts
+const STORAGE_PATH: &str = "chat.log";
+/// Rooms left empty this long are removed, unless an account owns them or
+/// they have a password
+const ROOM_IDLE_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+/// Rooms kept even when empty, "Main" always is
+const PERSISTENT_ROOMS: &[&str] = &[];
+/// Account owning "Main", it can name moderators and ban users there
+const MAIN_OWNER: Option<&str> = None;
+/// Messages a session may send per second, on average
+const MESSAGES_PER_SECOND: f64 = 5.0;
+/// Message bytes a session may send per second, on average
+const BYTES_PER_SECOND: f64 = 16.0 * 1024.0;
 
 /// Entry point for our route
 async fn chat_route(
@@ -24,8 +54,10 @@ async fn chat_route(
         WsChatSession {
             id: 0,
             hb: Instant::now(),
-            room: "Main".to_owned(),
-            name: None,
+            rooms: iter::once("Main".to_owned()).collect(),
+            room: Some("Main".to_owned()),
+            last_message: None,
+            peer: req.peer_addr().map(|addr| addr.ip()),
             addr: srv.get_ref().clone(),
         },
         &req,
@@ -39,10 +71,14 @@ struct WsChatSession {
     /// Client must send ping at least once per 10 seconds (CLIENT_TIMEOUT),
     /// otherwise we drop connection.
     hb: Instant,
-    /// joined room
-    room: String,
-    /// peer name
-    name: Option<String>,
+    /// joined rooms
+    rooms: HashSet<String>,
+    /// room plain messages go to, one of `rooms`
+    room: Option<String>,
+    /// id of the latest message sent, what `/edit` and `/delete` act on
+    last_message: Option<u64>,
+    /// address peer connects from, if known
+    peer: Option<IpAddr>,
     /// Chat server
     addr: Addr<server::ChatServer>,
 }
@@ -65,6 +101,8 @@ impl Actor for WsChatSession {
         self.addr
             .send(server::Connect {
                 addr: addr.recipient(),
+                transport: Transport::WebSocket,
+                peer: self.peer,
             })
             .into_actor(self)
             .then(|res, act, ctx| {
@@ -85,12 +123,21 @@ impl Actor for WsChatSession {
     }
 }
 
-/// Handle messages from chat server, we simply send it to peer websocket
+/// Handle messages from chat server, we send it to peer websocket as json
 impl Handler<session::Message> for WsChatSession {
     type Result = ();
 
     fn handle(&mut self, msg: session::Message, ctx: &mut Self::Context) {
-        ctx.text(msg.0);
+        // server already took us out of the room
+        if let ChatResponse::Kicked { room, session, .. } = &msg.0 {
+            if *session == self.id {
+                self.rooms.remove(room);
+                if self.room.as_ref() == Some(room) {
+                    self.room = self.rooms.iter().next().cloned();
+                }
+            }
+        }
+        ctx.text(json::to_string(&msg.0).unwrap());
     }
 }
 
@@ -109,7 +156,6 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for WsChatSession {
             Ok(msg) => msg,
         };
 
-        println!("WEBSOCKET MESSAGE: {:?}", msg);
         match msg {
             ws::Message::Ping(msg) => {
                 self.hb = Instant::now();
@@ -119,68 +165,393 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for WsChatSession {
                 self.hb = Instant::now();
             }
             ws::Message::Text(text) => {
-                let m = text.trim();
-                // we check for /sss type of messages
-                if m.starts_with('/') {
-                    let v: Vec<&str> = m.splitn(2, ' ').collect();
-                    match v[0] {
-                        "/list" => {
-                            // Send ListRooms message to chat server and wait for
-                            // response
-                            println!("List rooms");
-                            self.addr
-                                .send(server::ListRooms)
-                                .into_actor(self)
-                                .then(|res, _, ctx| {
-                                    match res {
-                                        Ok(rooms) => {
-                                            for room in rooms {
-                                                ctx.text(room);
-                                            }
+                let command = match commands::parse(text.trim()) {
+                    Ok(command) => command,
+                    Err(e) => {
+                        ctx.text(format!("!!! {}", e));
+                        return;
+                    }
+                };
+                match command {
+                    Command::List => {
+                        // Send ListRooms message to chat server and wait for
+                        // response
+                        println!("List rooms");
+                        self.addr
+                            .send(server::ListRooms { id: self.id })
+                            .into_actor(self)
+                            .then(|res, _, ctx| {
+                                match res {
+                                    Ok(rooms) => {
+                                        let rooms = ChatResponse::Rooms {
+                                            rooms,
+                                            request_id: None,
+                                        };
+                                        ctx.text(json::to_string(&rooms).unwrap());
+                                    }
+                                    _ => println!("Something is wrong"),
+                                }
+                                fut::ready(())
+                            })
+                            .wait(ctx)
+                        // .wait(ctx) pauses all events in context,
+                        // so actor wont receive any new messages until it get list
+                        // of rooms back
+                    }
+                    Command::Join { room, password } => self.join(ctx, room, password, None),
+                    Command::Create {
+                        room,
+                        visibility,
+                        password,
+                    } => self.join(ctx, room, password, Some(visibility)),
+                    Command::Leave(name) => {
+                        // current room unless told otherwise
+                        let name = match name.or_else(|| self.room.clone()) {
+                            Some(name) => name,
+                            None => {
+                                ctx.text("!!! room name is required");
+                                return;
+                            }
+                        };
+                        if !self.rooms.contains(&name) {
+                            ctx.text(format!("!!! not in room: {}", name));
+                            return;
+                        }
+                        self.addr
+                            .send(server::Leave {
+                                id: self.id,
+                                name: name.clone(),
+                            })
+                            .into_actor(self)
+                            .then(move |res, act, ctx| {
+                                match res {
+                                    Ok(Ok(())) => {
+                                        act.rooms.remove(&name);
+                                        if act.room.as_ref() == Some(&name) {
+                                            act.room = act.rooms.iter().next().cloned();
                                         }
-                                        _ => println!("Something is wrong"),
+                                        ctx.text("left");
                                     }
-                                    fut::ready(())
-                                })
-                                .wait(ctx)
-                            // .wait(ctx) pauses all events in context,
-                            // so actor wont receive any new messages until it get list
-                            // of rooms back
+                                    Ok(Err(e)) => act.refused(ctx, e),
+                                    _ => println!("Something is wrong"),
+                                }
+                                fut::ready(())
+                            })
+                            .wait(ctx)
+                    }
+                    Command::Room(name) => {
+                        if self.rooms.contains(&name) {
+                            self.room = Some(name);
+                        } else {
+                            ctx.text(format!("!!! not in room: {}", name));
                         }
-                        "/join" => {
-                            if v.len() == 2 {
-                                self.room = v[1].to_owned();
-                                self.addr.do_send(server::Join {
-                                    id: self.id,
-                                    name: self.room.clone(),
-                                });
-
-                                ctx.text("joined");
-                            } else {
+                    }
+                    Command::Name(name) => {
+                        // server announces the new name to us and our rooms
+                        self.addr
+                            .send(server::SetName { id: self.id, name })
+                            .into_actor(self)
+                            .then(|res, act, ctx| {
+                                match res {
+                                    Ok(Ok(())) => (),
+                                    Ok(Err(server::NameError::Throttled(e))) => act.refused(ctx, e),
+                                    Ok(Err(e)) => ctx.text(format!("!!! {}", e)),
+                                    _ => println!("Something is wrong"),
+                                }
+                                fut::ready(())
+                            })
+                            .wait(ctx)
+                    }
+                    Command::Direct { to, body } => self
+                        .addr
+                        .send(server::Direct {
+                            id: self.id,
+                            to: to.clone(),
+                            msg: body,
+                        })
+                        .into_actor(self)
+                        .then(move |res, act, ctx| {
+                            match res {
+                                Ok(Ok(())) => (),
+                                Ok(Err(server::RoomError::NoSuchUser)) => {
+                                    ctx.text(format!("!!! no such user: {}", to))
+                                }
+                                Ok(Err(e)) => act.refused(ctx, e),
+                                _ => println!("Something is wrong"),
+                            }
+                            fut::ready(())
+                        })
+                        .wait(ctx),
+                    Command::Register { user, password } => {
+                        let res = accounts::register(self.addr.clone(), self.id, user, password);
+                        self.logged_in(ctx, res);
+                    }
+                    Command::Login { user, password } => {
+                        let res = accounts::login(self.addr.clone(), self.id, user, password);
+                        self.logged_in(ctx, res);
+                    }
+                    Command::Moderate { action, user } => {
+                        let room = match &self.room {
+                            Some(room) => room.clone(),
+                            None => {
+                                ctx.text("!!! join a room first");
+                                return;
+                            }
+                        };
+                        self.addr
+                            .send(server::Moderate {
+                                id: self.id,
+                                room,
+                                user,
+                                action,
+                            })
+                            .into_actor(self)
+                            .then(|res, _, ctx| {
+                                match res {
+                                    Ok(Ok(())) => ctx.text("done"),
+                                    Ok(Err(e)) => ctx.text(format!("!!! {}", e)),
+                                    _ => println!("Something is wrong"),
+                                }
+                                fut::ready(())
+                            })
+                            .wait(ctx)
+                    }
+                    Command::Visibility(visibility) => {
+                        let room = match &self.room {
+                            Some(room) => room.clone(),
+                            None => {
+                                ctx.text("!!! join a room first");
+                                return;
+                            }
+                        };
+                        self.addr
+                            .send(server::SetVisibility {
+                                id: self.id,
+                                room,
+                                visibility,
+                            })
+                            .into_actor(self)
+                            .then(|res, _, ctx| {
+                                match res {
+                                    Ok(Ok(())) => ctx.text("done"),
+                                    Ok(Err(e)) => ctx.text(format!("!!! {}", e)),
+                                    _ => println!("Something is wrong"),
+                                }
+                                fut::ready(())
+                            })
+                            .wait(ctx)
+                    }
+                    Command::Password(password) => {
+                        let room = match &self.room {
+                            Some(room) => room.clone(),
+                            None => {
+                                ctx.text("!!! join a room first");
+                                return;
+                            }
+                        };
+                        rooms::set_password(self.addr.clone(), self.id, room, password)
+                            .into_actor(self)
+                            .then(|res, _, ctx| {
+                                match res {
+                                    Ok(()) => ctx.text("done"),
+                                    Err(e) => ctx.text(format!("!!! {}", e)),
+                                }
+                                fut::ready(())
+                            })
+                            .wait(ctx)
+                    }
+                    Command::Topic(topic) => {
+                        let room = match &self.room {
+                            Some(room) => room.clone(),
+                            None => {
+                                ctx.text("!!! join a room first");
+                                return;
+                            }
+                        };
+                        self.addr
+                            .send(server::SetTopic {
+                                id: self.id,
+                                room,
+                                topic,
+                            })
+                            .into_actor(self)
+                            .then(|res, _, ctx| {
+                                match res {
+                                    Ok(Ok(())) => (),
+                                    Ok(Err(e)) => ctx.text(format!("!!! {}", e)),
+                                    _ => println!("Something is wrong"),
+                                }
+                                fut::ready(())
+                            })
+                            .wait(ctx)
+                    }
+                    Command::Describe(description) => {
+                        let room = match &self.room {
+                            Some(room) => room.clone(),
+                            None => {
+                                ctx.text("!!! join a room first");
+                                return;
+                            }
+                        };
+                        self.addr
+                            .send(server::SetDescription {
+                                id: self.id,
+                                room,
+                                description,
+                            })
+                            .into_actor(self)
+                            .then(|res, _, ctx| {
+                                match res {
+                                    Ok(Ok(())) => ctx.text("done"),
+                                    Ok(Err(e)) => ctx.text(format!("!!! {}", e)),
+                                    _ => println!("Something is wrong"),
+                                }
+                                fut::ready(())
+                            })
+                            .wait(ctx)
+                    }
+                    Command::Edit(body) => {
+                        let message = match self.last_message {
+                            Some(message) => message,
+                            None => {
+                                ctx.text("!!! no message to edit");
+                                return;
+                            }
+                        };
+                        self.addr
+                            .send(server::EditMessage {
+                                id: self.id,
+                                message,
+                                body,
+                            })
+                            .into_actor(self)
+                            .then(|res, act, ctx| {
+                                match res {
+                                    // edited message comes back like any other
+                                    Ok(Ok(())) => (),
+                                    Ok(Err(e)) => act.refused(ctx, e),
+                                    _ => println!("Something is wrong"),
+                                }
+                                fut::ready(())
+                            })
+                            .wait(ctx)
+                    }
+                    Command::Delete(message) => {
+                        // own latest message unless told otherwise
+                        let message = match message.or(self.last_message) {
+                            Some(message) => message,
+                            None => {
+                                ctx.text("!!! message id is required");
+                                return;
+                            }
+                        };
+                        self.addr
+                            .send(server::DeleteMessage {
+                                id: self.id,
+                                message,
+                            })
+                            .into_actor(self)
+                            .then(|res, _, ctx| {
+                                match res {
+                                    Ok(Ok(())) => (),
+                                    Ok(Err(e)) => ctx.text(format!("!!! {}", e)),
+                                    _ => println!("Something is wrong"),
+                                }
+                                fut::ready(())
+                            })
+                            .wait(ctx)
+                    }
+                    Command::Who(room) => {
+                        // current room unless told otherwise
+                        let room = match room.or_else(|| self.room.clone()) {
+                            Some(room) => room,
+                            None => {
                                 ctx.text("!!! room name is required");
+                                return;
                             }
-                        }
-                        "/name" => {
-                            if v.len() == 2 {
-                                self.name = Some(v[1].to_owned());
-                            } else {
-                                ctx.text("!!! name is required");
+                        };
+                        self.addr
+                            .send(server::Who {
+                                id: self.id,
+                                room: room.clone(),
+                            })
+                            .into_actor(self)
+                            .then(move |res, _, ctx| {
+                                match res {
+                                    Ok(Some(members)) => {
+                                        let members = ChatResponse::Members {
+                                            room,
+                                            members,
+                                            request_id: None,
+                                        };
+                                        ctx.text(json::to_string(&members).unwrap());
+                                    }
+                                    Ok(None) => ctx.text(format!("!!! no such room: {}", room)),
+                                    _ => println!("Something is wrong"),
+                                }
+                                fut::ready(())
+                            })
+                            .wait(ctx)
+                    }
+                    Command::History(before) => {
+                        // page back with the id of the oldest message seen
+                        let room = match &self.room {
+                            Some(room) => room.clone(),
+                            None => {
+                                ctx.text("!!! join a room first");
+                                return;
                             }
-                        }
-                        _ => ctx.text(format!("!!! unknown command: {:?}", m)),
-                    }
-                } else {
-                    let msg = if let Some(ref name) = self.name {
-                        format!("{}: {}", name, m)
-                    } else {
-                        m.to_owned()
-                    };
-                    // send message to chat server
-                    self.addr.do_send(server::Message {
-                        id: self.id,
-                        msg,
-                        room: self.room.clone(),
-                    })
+                        };
+                        self.addr
+                            .send(server::History {
+                                id: self.id,
+                                room: room.clone(),
+                                before,
+                                limit: server::HISTORY_PAGE_SIZE,
+                            })
+                            .into_actor(self)
+                            .then(move |res, _, ctx| {
+                                match res {
+                                    Ok(Some(messages)) => {
+                                        let history = ChatResponse::History {
+                                            room,
+                                            messages,
+                                            request_id: None,
+                                        };
+                                        ctx.text(json::to_string(&history).unwrap());
+                                    }
+                                    _ => println!("Something is wrong"),
+                                }
+                                fut::ready(())
+                            })
+                            .wait(ctx)
+                    }
+                    Command::Message(body) => {
+                        let room = match &self.room {
+                            Some(room) => room.clone(),
+                            None => {
+                                ctx.text("!!! join a room first");
+                                return;
+                            }
+                        };
+                        // send message to chat server
+                        self.addr
+                            .send(server::Message {
+                                id: self.id,
+                                msg: body,
+                                room,
+                            })
+                            .into_actor(self)
+                            .then(|res, act, ctx| {
+                                match res {
+                                    Ok(Ok(id)) => act.last_message = Some(id),
+                                    Ok(Err(e)) => act.refused(ctx, e),
+                                    _ => println!("Something is wrong"),
+                                }
+                                fut::ready(())
+                            })
+                            .wait(ctx)
+                    }
                 }
             }
             ws::Message::Binary(_) => println!("Unexpected binary"),
@@ -194,6 +565,65 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for WsChatSession {
 }
 
 impl WsChatSession {
+    /// Join room `name` and make it the current room
+    fn join(
+        &mut self,
+        ctx: &mut ws::WebsocketContext<Self>,
+        name: String,
+        password: Option<String>,
+        visibility: Option<Visibility>,
+    ) {
+        rooms::join(
+            self.addr.clone(),
+            self.id,
+            name.clone(),
+            password,
+            visibility,
+        )
+        .into_actor(self)
+        .then(move |res, act, ctx| {
+            match res {
+                Ok(()) => {
+                    act.rooms.insert(name.clone());
+                    act.room = Some(name);
+                    ctx.text("joined");
+                }
+                Err(e @ server::RoomError::Flooding) => act.refused(ctx, e),
+                Err(e) => ctx.text(format!("!!! {}: {}", e, name)),
+            }
+            fut::ready(())
+        })
+        .wait(ctx);
+    }
+
+    /// Tell peer whether `Register` or `Login` succeeded
+    fn logged_in(
+        &mut self,
+        ctx: &mut ws::WebsocketContext<Self>,
+        res: impl Future<Output = Result<String, AccountError>> + 'static,
+    ) {
+        res.into_actor(self)
+            .then(|res, act, ctx| {
+                match res {
+                    Ok(user) => ctx.text(format!("logged in as {}", user)),
+                    Err(AccountError::Throttled(e)) => act.refused(ctx, e),
+                    Err(e) => ctx.text(format!("!!! {}", e)),
+                }
+                fut::ready(())
+            })
+            .wait(ctx)
+    }
+
+    /// Tell peer why its message or request was refused, closing the
+    /// connection if it keeps flooding
+    fn refused(&self, ctx: &mut ws::WebsocketContext<Self>, e: server::RoomError) {
+        ctx.text(format!("!!! {}", e));
+        if e == server::RoomError::Flooding {
+            ctx.close(Some(ws::CloseCode::Policy.into()));
+            ctx.stop();
+        }
+    }
+
     /// helper method that sends ping to client every second.
     ///
     /// also this method checks heartbeats from client
@@ -224,11 +654,23 @@ async fn main() -> std::io::Result<()> {
     env_logger::init();
 
     // Start chat server actor
-    let server = server::ChatServer::default().start();
+    let gc = server::RoomGc {
+        idle: Some(ROOM_IDLE_TIMEOUT),
+        persistent: PERSISTENT_ROOMS.iter().map(|r| (*r).to_owned()).collect(),
+    };
+    let rate_limit = flood::RateLimit {
+        messages_per_second: MESSAGES_PER_SECOND,
+        bytes_per_second: BYTES_PER_SECOND,
+        ..flood::RateLimit::default()
+    };
+    let server = server::ChatServer::open(STORAGE_PATH, gc, Some(rate_limit), MAIN_OWNER)?.start();
 
     // Start tcp server in separate thread
     let srv = server.clone();
-    session::tcp_server("127.0.0.1:12345", srv);
+    session::tcp_server("127.0.0.1:12345", srv, MAX_FRAME_SIZE);
+
+    // Plain text tcp server for netcat/telnet users
+    text_session::text_server("127.0.0.1:12346", server.clone());
 
     println!("Started http server: 127.0.0.1:8080");
 
@@ -0,0 +1,109 @@
+//! Joining rooms and protecting them with a password.
+//!
+//! Room passwords are salted argon2 hashes like account passwords, so they are
+//! hashed and checked on the blocking thread pool, never inside `ChatServer`.
+//! Wrong passwords make both the room and the address they came from wait
+//! before the next attempt, the same way failed logins do.
+
+use actix::prelude::*;
+use actix_web::web;
+
+use tcp_chat::codec::Visibility;
+
+use crate::accounts;
+use crate::server::{self, ChatServer, RoomError};
+
+/// Let session `id` join `room`, creating it with `password` and `visibility`
+/// if it doesn't exist. Password is checked if room has one.
+pub async fn join(
+    server: Addr<ChatServer>,
+    id: usize,
+    room: String,
+    password: Option<String>,
+    visibility: Option<Visibility>,
+) -> Result<(), RoomError> {
+    let (password_ok, hash) = match server
+        .send(server::RoomPassword {
+            id,
+            room: room.clone(),
+        })
+        .await??
+    {
+        // room doesn't exist, joining creates it
+        None => match password {
+            Some(password) => (false, Some(hash(password).await?)),
+            None => (false, None),
+        },
+        Some(Some(hash)) => match password {
+            Some(password) => (
+                attempt(server.clone(), id, room.clone(), password, hash).await?,
+                None,
+            ),
+            None => (false, None),
+        },
+        Some(None) => (false, None),
+    };
+
+    server
+        .send(server::Join {
+            id,
+            name: room,
+            password_ok,
+            hash,
+            visibility: visibility.unwrap_or_default(),
+        })
+        .await?
+}
+
+/// Check `password` of `room` against its `hash` unless session `id` has to
+/// wait, and tell server how it went
+async fn attempt(
+    server: Addr<ChatServer>,
+    id: usize,
+    room: String,
+    password: String,
+    hash: String,
+) -> Result<bool, RoomError> {
+    server
+        .send(server::AttemptJoin {
+            id,
+            room: room.clone(),
+        })
+        .await??;
+    let valid = verify(password, hash).await?;
+    server.do_send(server::JoinAttempted {
+        id,
+        room,
+        succeeded: valid,
+    });
+    Ok(valid)
+}
+
+/// Protect `room` with `password` on behalf of session `id`, or make it open
+/// again if `None`
+pub async fn set_password(
+    server: Addr<ChatServer>,
+    id: usize,
+    room: String,
+    password: Option<String>,
+) -> Result<(), RoomError> {
+    let hash = match password {
+        Some(password) => Some(hash(password).await?),
+        None => None,
+    };
+    server.send(server::SetPassword { id, room, hash }).await?
+}
+
+/// Salted hash of room `password`
+async fn hash(password: String) -> Result<String, RoomError> {
+    web::block(move || accounts::hash_password(&password))
+        .await
+        .map_err(|_| RoomError::Internal)
+}
+
+/// Whether `password` matches room password `hash`
+async fn verify(password: String, hash: String) -> Result<bool, RoomError> {
+    web::block(move || accounts::verify_password(&password, &hash))
+        .await
+        .map_err(|_| RoomError::Internal)
+}
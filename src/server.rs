@@ -2,20 +2,37 @@
 //! And manages available rooms. Peers send messages to other peers in same
 //! room through `ChatServer`.
 
-use std::collections::{HashMap, HashSet};
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::net::IpAddr;
+use std::path::Path;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use std::{fmt, io, iter};
 
 use actix::prelude::*;
 use rand::{self, Rng, rngs::ThreadRng};
 
+use tcp_chat::codec::{
+    ChatMessage, ChatResponse, DirectMessage, ErrorCode, Member, RoomInfo, Transport, Visibility,
+};
+
+use crate::accounts::AccountError;
+use crate::flood::{Limiter, RateLimit, Verdict};
 use crate::session;
+use crate::storage::{Record, Storage};
 
 /// Message for chat server communications
 
 /// New chat session is created
+#[allow(clippy::empty_line_after_doc_comments)]
 #[derive(Message)]
 #[rtype(usize)]
 pub struct Connect {
     pub addr: Recipient<session::Message>,
+    /// How the session is connected, reported by `Who`
+    pub transport: Transport,
+    /// Address the session connects from, if known
+    pub peer: Option<IpAddr>,
 }
 
 /// Session is disconnected
@@ -25,9 +42,11 @@ pub struct Disconnect {
     pub id: usize,
 }
 
-/// Send message to specefic room
+/// Send message to specefic room, refused if it is too long, session isn't in
+/// the room, is muted there or sends too fast. Result is the id of the
+/// message.
 #[derive(Message)]
-#[rtype(result = "()")]
+#[rtype(result = "Result<u64, RoomError>")]
 pub struct Message {
     /// Id of the client session
     pub id: usize,
@@ -37,160 +56,2518 @@ pub struct Message {
     pub room: String,
 }
 
-/// List of available rooms
-pub struct ListRooms;
-
-impl actix::Message for ListRooms {
-    type Result = Vec<String>;
+/// Replace body of a message still in room history, author only
+#[derive(Message)]
+#[rtype(result = "Result<(), RoomError>")]
+pub struct EditMessage {
+    /// Client id
+    pub id: usize,
+    /// Message id
+    pub message: u64,
+    /// New body
+    pub body: String,
 }
 
-/// Join room, if room doesnt exist create new one.
+/// Delete a message still in room history, its author and room moderators only
 #[derive(Message)]
-#[rtype(result = "()")]
-pub struct Join {
+#[rtype(result = "Result<(), RoomError>")]
+pub struct DeleteMessage {
     /// Client id
     pub id: usize,
-    /// Room name
+    /// Message id
+    pub message: u64,
+}
+
+/// Send private message to a user, bypassing rooms. Refused if it is too long,
+/// nobody goes by `to` or session sends too fast.
+pub struct Direct {
+    /// Id of the sending session
+    pub id: usize,
+    /// Name of the recipient, or `#id` of its session
+    pub to: String,
+    /// peer message
+    pub msg: String,
+}
+
+impl actix::Message for Direct {
+    type Result = Result<(), RoomError>;
+}
+
+/// Session picks a nickname, refused if invalid or used by another session
+pub struct SetName {
+    /// Client id
+    pub id: usize,
+    /// New name
     pub name: String,
 }
 
-/// ChatServer manages chat rooms and responsible for coordinating chat session. implementation is super primitive
-pub struct ChatServer {
-    sessions: HashMap<usize, Recipient<session::Message>>,
-    rooms: HashMap<String, HashSet<usize>>,
-    rng: ThreadRng,
+impl actix::Message for SetName {
+    type Result = Result<(), NameError>;
 }
 
-impl Default for ChatServer {
-    fn default() -> Self {
-        // default room
-        let mut rooms = HashMap::new();
-        rooms.insert("Main".to_owned(), HashSet::new());
+/// Longest nickname accepted, in characters
+const MAX_NAME_LENGTH: usize = 32;
 
-        ChatServer {
-            sessions: HashMap::new(),
-            rooms,
-            rng: rand::thread_rng(),
+/// Why a nickname was refused
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum NameError {
+    /// Empty, too long or has characters other than letters, digits, `_`, `-` and `.`
+    Invalid,
+    /// Another session or a registered account goes by this name, names
+    /// differing in case only clash
+    Taken,
+    /// Session is logged in, it goes by its account name
+    LoggedIn,
+    /// Session sends requests too fast, flood protection refused this one
+    Throttled(RoomError),
+}
+
+impl NameError {
+    /// Code reported to binary protocol peers
+    pub fn code(self) -> ErrorCode {
+        match self {
+            NameError::Invalid => ErrorCode::InvalidName,
+            NameError::Taken => ErrorCode::NameTaken,
+            NameError::LoggedIn => ErrorCode::InvalidRequest,
+            NameError::Throttled(e) => e.code(),
         }
     }
 }
 
-impl ChatServer {
-    /// Send message to all users in the room
-    fn send_message(&self, room: &str, message: &str, skip_id: usize) {
-        if let Some(sessions) = self.rooms.get(room) {
-            for id in sessions {
-                if *id != skip_id {
-                    if let Some(addr) = self.sessions.get(id) {
-                        let _ = addr.do_send(session::Message(message.to_owned()));
-                    }
-                }
-            }
+impl fmt::Display for NameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NameError::Invalid => write!(
+                f,
+                "name must be 1 to {} ASCII letters, digits, '_', '-' or '.'",
+                MAX_NAME_LENGTH
+            ),
+            NameError::Taken => write!(f, "name is already taken"),
+            NameError::LoggedIn => write!(f, "logged in users go by their account name"),
+            NameError::Throttled(e) => write!(f, "{}", e),
         }
     }
 }
 
-/// Make actor from ChatServer
-impl Actor for ChatServer {
-    /// we are going to use simple context we just need ability to communicate
-    /// with other actors
-    type Context = Context<Self>;
+/// Whether `name` is acceptable as nickname. ASCII only, so nobody can pass
+/// for someone else with look-alike letters of another script.
+pub fn valid_name(name: &str) -> bool {
+    (1..=MAX_NAME_LENGTH).contains(&name.len())
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-' || c == '.')
 }
 
-/// Handler for Connect message.
+/// Create account and log session in to it
+pub struct Register {
+    /// Client id
+    pub id: usize,
+    /// Account name, becomes nickname of the session
+    pub user: String,
+    /// Salted password hash
+    pub hash: String,
+}
+
+impl actix::Message for Register {
+    type Result = Result<(), AccountError>;
+}
+
+/// Let session try to register or log in to account `user`, refused while
+/// the account or the address of the session waits out failed attempts
+pub struct AttemptLogin {
+    /// Client id
+    pub id: usize,
+    /// Account name
+    pub user: String,
+}
+
+impl actix::Message for AttemptLogin {
+    type Result = Result<(), AccountError>;
+}
+
+/// Outcome of a register or login attempt. Every failure in a row doubles
+/// how long the account and the address of the session have to wait before
+/// the next attempt.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct LoginAttempted {
+    /// Client id
+    pub id: usize,
+    /// Account name
+    pub user: String,
+    pub succeeded: bool,
+}
+
+/// Wait after the first failed login or register attempt
+const LOGIN_BACKOFF: Duration = Duration::from_secs(1);
+/// Longest wait after failed login or register attempts
+const MAX_LOGIN_BACKOFF: Duration = Duration::from_secs(60);
+/// Failed attempts are forgotten once their wait is over for this long
+const LOGIN_FORGIVE: Duration = Duration::from_secs(15 * 60);
+
+/// Password hash of an account, `None` if there is no such account
+pub struct PasswordHash {
+    pub user: String,
+}
+
+impl actix::Message for PasswordHash {
+    type Result = Option<String>;
+}
+
+/// Log session in to an account, password has been checked already
+pub struct Login {
+    /// Client id
+    pub id: usize,
+    /// Account name, becomes nickname of the session
+    pub user: String,
+}
+
+impl actix::Message for Login {
+    /// Account name as registered
+    type Result = Result<String, AccountError>;
+}
+
+/// Members of a room, `None` if room doesn't exist or session may not look
+/// into it
+pub struct Who {
+    /// Client id
+    pub id: usize,
+    /// Room name
+    pub room: String,
+}
+
+impl actix::Message for Who {
+    type Result = Option<Vec<Member>>;
+}
+
+/// List of rooms session can see, hidden rooms are listed to their members
+/// and invited users only
+pub struct ListRooms {
+    /// Client id
+    pub id: usize,
+}
+
+impl actix::Message for ListRooms {
+    type Result = Vec<RoomInfo>;
+}
+
+/// Fetch recent messages of a room, `None` if room doesn't exist or session
+/// may not look into it
+pub struct History {
+    /// Client id
+    pub id: usize,
+    /// Room name
+    pub room: String,
+    /// Only messages with smaller id, most recent ones if `None`
+    pub before: Option<u64>,
+    /// Maximum number of messages returned
+    pub limit: usize,
+}
+
+impl actix::Message for History {
+    type Result = Option<Vec<ChatMessage>>;
+}
+
+/// Join room, if room doesnt exist create new one and make session its owner.
 ///
-/// Register new session and assign unique id to this session
-impl Handler<Connect> for ChatServer {
-    type Result = usize;
+/// Refused if session is banned from the room. Invite-only rooms need an
+/// invitation, password protected ones an invitation or the password.
+#[derive(Message)]
+#[rtype(result = "Result<(), RoomError>")]
+pub struct Join {
+    /// Client id
+    pub id: usize,
+    /// Room name
+    pub name: String,
+    /// Session gave the room password, checked against `RoomPassword`
+    pub password_ok: bool,
+    /// Password hash of the room if this creates it
+    pub hash: Option<String>,
+    /// Visibility of the room if this creates it
+    pub visibility: Visibility,
+}
 
-    fn handle(&mut self, msg: Connect, _: &mut Self::Context) -> Self::Result {
-        println!("Someone joined ");
+/// Let session try the password of `room`, refused while the room or the
+/// address of the session waits out wrong passwords
+pub struct AttemptJoin {
+    /// Client id
+    pub id: usize,
+    /// Room name
+    pub room: String,
+}
 
-        // notify all users in same room
-        self.send_message(&"Main".to_owned(), "Someone joined", 0);
+impl actix::Message for AttemptJoin {
+    type Result = Result<(), RoomError>;
+}
 
-        // register session with random id
-        let id = self.rng.gen::<usize>();
-        self.sessions.insert(id, msg.addr);
+/// Outcome of trying a room password. Every wrong one in a row doubles how
+/// long the room and the address of the session have to wait before the
+/// next attempt.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct JoinAttempted {
+    /// Client id
+    pub id: usize,
+    /// Room name
+    pub room: String,
+    pub succeeded: bool,
+}
 
-        // auto join session to Main room
-        self.rooms.get_mut(&"Main".to_owned()).unwrap().insert(id);
+/// Password hash of a room, `None` if room doesn't exist, `Some(None)` if
+/// it has no password. Asked first thing when session `id` joins a room, so
+/// that is where joining is charged to its rate limit.
+pub struct RoomPassword {
+    /// Client id
+    pub id: usize,
+    /// Room name
+    pub room: String,
+}
 
-        // send id back
-        id
-    }
+impl actix::Message for RoomPassword {
+    type Result = Result<Option<Option<String>>, RoomError>;
 }
 
-/// Handler for Disconnect message.
-impl Handler<Disconnect> for ChatServer {
-    type Result = ();
+/// Change who can find and join a room, room owner only
+#[derive(Message)]
+#[rtype(result = "Result<(), RoomError>")]
+pub struct SetVisibility {
+    /// Client id
+    pub id: usize,
+    /// Room name
+    pub room: String,
+    pub visibility: Visibility,
+}
 
-    fn handle(&mut self, msg: Disconnect, _: &mut Self::Context) -> Self::Result {
-        println!("Someone disconnected");
+/// Protect room with a password, or make it open again, room owner only
+#[derive(Message)]
+#[rtype(result = "Result<(), RoomError>")]
+pub struct SetPassword {
+    /// Client id
+    pub id: usize,
+    /// Room name
+    pub room: String,
+    /// Salted password hash, `None` to remove password
+    pub hash: Option<String>,
+}
 
-        let mut rooms: Vec<String> = Vec::new();
+/// Longest message body accepted, in bytes. Well below the frame size, so a
+/// message fits a frame along with its envelope.
+const MAX_MESSAGE_LENGTH: usize = 16 * 1024;
+/// Longest room topic accepted, in characters
+const MAX_TOPIC_LENGTH: usize = 256;
+/// Longest room description accepted, in characters
+const MAX_DESCRIPTION_LENGTH: usize = 1024;
 
-        // remove address
-        if self.sessions.remove(&msg.id).is_some() {
-            // remove session from all roms
-            for (name, sessions) in &mut self.rooms {
-                if sessions.remove(&msg.id) {
-                    rooms.push(name.to_owned());
-                }
+/// Change topic of a room, room owner and moderators only. Members are told
+/// about the new topic.
+#[derive(Message)]
+#[rtype(result = "Result<(), RoomError>")]
+pub struct SetTopic {
+    /// Client id
+    pub id: usize,
+    /// Room name
+    pub room: String,
+    /// New topic, `None` or empty to clear it
+    pub topic: Option<String>,
+}
+
+/// Change description of a room, room owner only
+#[derive(Message)]
+#[rtype(result = "Result<(), RoomError>")]
+pub struct SetDescription {
+    /// Client id
+    pub id: usize,
+    /// Room name
+    pub room: String,
+    /// New description, `None` or empty to clear it
+    pub description: Option<String>,
+}
+
+/// Leave room, other memberships of the session are kept
+#[derive(Message)]
+#[rtype(result = "Result<(), RoomError>")]
+pub struct Leave {
+    /// Client id
+    pub id: usize,
+    /// Room name
+    pub name: String,
+}
+
+/// What a moderator does to a user of a room
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Moderation {
+    /// Remove from the room, user may join again
+    Kick,
+    /// Remove from the room and keep out. Guests are kept out by the address
+    /// they connect from as well, so they can't just reconnect, until the
+    /// server restarts.
+    Ban,
+    /// Let banned user, and guests from its address, join again
+    Unban,
+    /// Keep from posting to the room
+    Mute,
+    /// Let muted user post again
+    Unmute,
+    /// Let user into invite-only or password protected room
+    Invite,
+    /// Make moderator, owner only
+    AddModerator,
+    /// Take moderator role away, owner only
+    RemoveModerator,
+}
+
+/// Moderate a user of a room on behalf of session `id`.
+///
+/// Owner of a room can do anything to anyone else in it, moderators can act on
+/// plain members only.
+#[derive(Message)]
+#[rtype(result = "Result<(), RoomError>")]
+pub struct Moderate {
+    /// Client id
+    pub id: usize,
+    /// Room name
+    pub room: String,
+    /// Name of the user acted on, or `#id` of its session
+    pub user: String,
+    pub action: Moderation,
+}
+
+/// Why a request concerning a room was refused
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RoomError {
+    /// Room doesn't exist
+    NoSuchRoom,
+    /// Nobody goes by the name and there is no such account
+    NoSuchUser,
+    /// Session, or the user acted on, is not in the room
+    NotInRoom,
+    /// Session's role in the room doesn't allow the request
+    PermissionDenied,
+    /// Session is banned from the room
+    Banned,
+    /// Session is muted in the room
+    Muted,
+    /// Room is invite-only and session wasn't invited
+    NotInvited,
+    /// Room password is missing or wrong
+    WrongPassword,
+    /// Room or address of the session got the password wrong too often, it
+    /// has to wait before trying again
+    TooManyAttempts,
+    /// Topic or description is longer than allowed
+    TooLong,
+    /// Message body is longer than `MAX_MESSAGE_LENGTH`
+    MessageTooLong,
+    /// Session sends too fast, message was dropped
+    RateLimited,
+    /// Session is muted for sending too fast
+    FloodMuted,
+    /// Session kept sending too fast, it has to be disconnected
+    Flooding,
+    /// Message doesn't exist or fell out of history
+    NoSuchMessage,
+    /// Server failed to handle request
+    Internal,
+}
+
+impl RoomError {
+    /// Code reported to binary protocol peers
+    pub fn code(self) -> ErrorCode {
+        match self {
+            RoomError::NoSuchRoom => ErrorCode::NoSuchRoom,
+            RoomError::NoSuchUser => ErrorCode::NoSuchUser,
+            RoomError::NotInRoom => ErrorCode::NotInRoom,
+            RoomError::PermissionDenied => ErrorCode::PermissionDenied,
+            RoomError::Banned => ErrorCode::Banned,
+            RoomError::Muted => ErrorCode::Muted,
+            RoomError::NotInvited => ErrorCode::NotInvited,
+            RoomError::WrongPassword => ErrorCode::InvalidCredentials,
+            RoomError::TooLong | RoomError::MessageTooLong => ErrorCode::InvalidRequest,
+            RoomError::TooManyAttempts
+            | RoomError::RateLimited
+            | RoomError::FloodMuted
+            | RoomError::Flooding => ErrorCode::RateLimited,
+            RoomError::NoSuchMessage => ErrorCode::NoSuchMessage,
+            RoomError::Internal => ErrorCode::Internal,
+        }
+    }
+}
+
+impl fmt::Display for RoomError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RoomError::NoSuchRoom => write!(f, "no such room"),
+            RoomError::NoSuchUser => write!(f, "no such user"),
+            RoomError::NotInRoom => write!(f, "not in room"),
+            RoomError::PermissionDenied => write!(f, "permission denied"),
+            RoomError::Banned => write!(f, "banned from room"),
+            RoomError::Muted => write!(f, "muted in room"),
+            RoomError::NotInvited => write!(f, "room is invite-only"),
+            RoomError::WrongPassword => write!(f, "wrong room password"),
+            RoomError::TooManyAttempts => {
+                write!(f, "too many wrong passwords, try again later")
             }
+            RoomError::TooLong => write!(
+                f,
+                "topic must be at most {} and description at most {} characters",
+                MAX_TOPIC_LENGTH, MAX_DESCRIPTION_LENGTH
+            ),
+            RoomError::MessageTooLong => {
+                write!(f, "message must be at most {} bytes", MAX_MESSAGE_LENGTH)
+            }
+            RoomError::RateLimited => write!(f, "sending too fast, message dropped"),
+            RoomError::FloodMuted => write!(f, "muted for sending too fast"),
+            RoomError::Flooding => write!(f, "disconnected for flooding"),
+            RoomError::NoSuchMessage => write!(f, "no such message"),
+            RoomError::Internal => write!(f, "could not handle room request"),
         }
-        // send message to other users
-        for room in rooms {
-            self.send_message(&room, "Someone disconnected", 0);
+    }
+}
+
+impl From<MailboxError> for RoomError {
+    fn from(_: MailboxError) -> Self {
+        RoomError::Internal
+    }
+}
+
+/// Hands out session ids.
+///
+/// Ids are drawn from a cryptographically secure generator so they can't be
+/// guessed, and are checked against live sessions so they never collide.
+struct SessionIds {
+    rng: ThreadRng,
+}
+
+impl SessionIds {
+    fn new() -> SessionIds {
+        SessionIds {
+            rng: rand::thread_rng(),
+        }
+    }
+
+    /// Fresh id, not used by any of `sessions`
+    fn allocate<T>(&mut self, sessions: &HashMap<usize, T>) -> usize {
+        loop {
+            let id = self.rng.gen::<usize>();
+            if !sessions.contains_key(&id) {
+                return id;
+            }
         }
     }
 }
 
-/// Handler for Message message
-impl Handler<Message> for ChatServer {
-    type Result = ();
-    fn handle(&mut self, msg: Message, _: &mut Self::Context) -> Self::Result {
-        self.send_message(&msg.room, msg.msg.as_str(), msg.id);
+/// How often empty rooms are looked for
+const GC_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Clean up of rooms nobody is in.
+///
+/// Rooms owned by an account or protected by a password are kept, so nobody
+/// can take over their name along with ownership or the way in.
+#[derive(Clone, Default)]
+pub struct RoomGc {
+    /// Remove rooms that stayed empty this long, keep them forever if `None`.
+    /// Rooms are looked at every `GC_INTERVAL`, so removal may come that much later.
+    pub idle: Option<Duration>,
+    /// Rooms kept even when empty, "Main" always is
+    pub persistent: HashSet<String>,
+}
+
+/// Number of recent messages kept per room
+const HISTORY_SIZE: usize = 200;
+/// Number of messages fetched by one `/history` command
+pub const HISTORY_PAGE_SIZE: usize = 20;
+/// Message bytes in one page of history at most, so the page fits a frame
+const HISTORY_PAGE_BYTES: usize = 256 * 1024;
+
+/// Who a room role, ban or mute applies to
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Identity {
+    /// Logged in user, by lower case account name. Survives reconnects and
+    /// restarts.
+    Account(String),
+    /// Anonymous session, forgotten once it disconnects
+    Guest(usize),
+}
+
+impl Identity {
+    /// Account name, `None` for guests
+    fn account(&self) -> Option<&str> {
+        match self {
+            Identity::Account(user) => Some(user),
+            Identity::Guest(_) => None,
+        }
     }
 }
 
-/// Handler for ListRooms message
-impl Handler<ListRooms> for ChatServer {
-    type Result = MessageResult<ListRooms>;
+/// Role of a user in a room, higher roles can moderate lower ones
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Role {
+    Member,
+    Moderator,
+    Owner,
+}
 
-    fn handle(&mut self, _: ListRooms, _: &mut Self::Context) -> Self::Result {
-        let mut rooms = Vec::new();
+/// Current server time, milliseconds since unix epoch
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+/// Chat room, its members and most recent messages
+struct Room {
+    sessions: HashSet<usize>,
+    /// oldest message first, at most `HISTORY_SIZE` messages
+    history: VecDeque<ChatMessage>,
+    /// when garbage collection first found room empty
+    empty_since: Option<Instant>,
+    /// who created the room, `None` for rooms the server made
+    owner: Option<Identity>,
+    moderators: HashSet<Identity>,
+    /// may not join the room
+    banned: HashSet<Identity>,
+    /// addresses of banned guests, guests connecting from them may not join
+    /// either
+    banned_peers: HashSet<IpAddr>,
+    /// may not post to the room
+    muted: HashSet<Identity>,
+    /// may join even if room is invite-only or has a password
+    invited: HashSet<Identity>,
+    visibility: Visibility,
+    /// salted hash of the join password, open room if `None`
+    password: Option<String>,
+    topic: Option<String>,
+    description: Option<String>,
+    /// server time room was created, milliseconds since unix epoch
+    created: u64,
+}
 
-        for key in self.rooms.keys() {
-            rooms.push(key.to_owned())
+/// Empty public room, created now
+impl Default for Room {
+    fn default() -> Self {
+        Room {
+            sessions: HashSet::new(),
+            history: VecDeque::new(),
+            empty_since: None,
+            owner: None,
+            moderators: HashSet::new(),
+            banned: HashSet::new(),
+            banned_peers: HashSet::new(),
+            muted: HashSet::new(),
+            invited: HashSet::new(),
+            visibility: Visibility::default(),
+            password: None,
+            topic: None,
+            description: None,
+            created: now(),
         }
-        MessageResult(rooms)
     }
 }
 
-/// Join room, send message id to old room
-/// send join message to new room
-impl Handler<Join> for ChatServer {
-    type Result = ();
+impl Room {
+    /// Room as listed to sessions
+    fn info(&self, name: &str) -> RoomInfo {
+        RoomInfo {
+            name: name.to_owned(),
+            topic: self.topic.clone(),
+            description: self.description.clone(),
+            created: self.created,
+            members: self.sessions.len(),
+            last_activity: self.history.back().map_or(self.created, |m| m.timestamp),
+        }
+    }
 
-    fn handle(&mut self, msg: Join, _: &mut Self::Context) -> Self::Result {
-        let Join { id, name } = msg;
-        let mut rooms = Vec::new();
+    /// Whether garbage collection has to keep the room even when it is empty
+    fn is_kept(&self) -> bool {
+        self.owner.as_ref().and_then(Identity::account).is_some() || self.password.is_some()
+    }
 
-        // remove session from all rooms
-        for (n, sessions) in &mut self.rooms {
-            if sessions.remove(&id) {
-                rooms.push(n.to_owned());
-            }
+    fn role(&self, who: &Identity) -> Role {
+        if self.owner.as_ref() == Some(who) {
+            Role::Owner
+        } else if self.moderators.contains(who) {
+            Role::Moderator
+        } else {
+            Role::Member
         }
-        // send message to other users
-        for room in rooms {
-            self.send_message(&room, "Someone disconnected", 0);
+    }
+
+    /// Whether `who` may join without knowing the password, even if room is
+    /// invite-only
+    fn is_invited(&self, who: &Identity) -> bool {
+        self.invited.contains(who) || self.role(who) > Role::Member
+    }
+
+    /// Whether session `id` of `who` finds the room listed
+    fn is_visible_to(&self, id: usize, who: &Identity) -> bool {
+        self.visibility == Visibility::Public || self.sessions.contains(&id) || self.is_invited(who)
+    }
+
+    /// Whether `who`, connecting from `peer`, is banned from the room.
+    /// Logged in users answer for their account, not for their address.
+    fn is_banned(&self, who: &Identity, peer: Option<IpAddr>) -> bool {
+        let guest = matches!(who, Identity::Guest(_));
+        self.banned.contains(who)
+            || (guest && peer.is_some_and(|peer| self.banned_peers.contains(&peer)))
+    }
+
+    /// Whether session `id` of `who`, connecting from `peer`, may see members
+    /// and history of the room. Banned users may not, rooms that aren't
+    /// public and open show to their members and invited users only.
+    fn is_readable_by(&self, id: usize, who: &Identity, peer: Option<IpAddr>) -> bool {
+        if self.is_banned(who, peer) {
+            return false;
         }
-        if self.rooms.get_mut(&name).is_none() {
-            self.rooms.insert(name.clone(), HashSet::new());
+        let open = self.visibility == Visibility::Public && self.password.is_none();
+        open || self.sessions.contains(&id) || self.is_invited(who)
+    }
+
+    /// Remember message, forgetting the oldest one once history is full
+    fn record(&mut self, message: ChatMessage) {
+        if self.history.len() == HISTORY_SIZE {
+            self.history.pop_front();
         }
-        self.send_message(&name, "Someone connected", id);
-        self.rooms.get_mut(&name).unwrap().insert(id);
+        self.history.push_back(message);
+    }
+
+    /// Message `id`, if it is still in history
+    fn message_mut(&mut self, id: u64) -> Option<&mut ChatMessage> {
+        // ids only ever grow, history is sorted by them
+        let index = self.history.binary_search_by_key(&id, |m| m.id).ok()?;
+        self.history.get_mut(index)
+    }
+
+    /// Up to `limit` most recent messages older than `before`, oldest first.
+    /// Fewer if their bodies add up to more than `HISTORY_PAGE_BYTES`.
+    fn history(&self, before: Option<u64>, limit: usize) -> Vec<ChatMessage> {
+        let end = match before {
+            Some(before) => self.history.iter().take_while(|m| m.id < before).count(),
+            None => self.history.len(),
+        };
+        let mut bytes = 0;
+        let count = self
+            .history
+            .range(..end)
+            .rev()
+            .take(limit)
+            .take_while(|m| {
+                bytes += m.body.len();
+                bytes <= HISTORY_PAGE_BYTES
+            })
+            .count();
+        self.history.range(end - count..end).cloned().collect()
     }
-}
\ No newline at end of file
+}
+
+/// Registered user
+struct Account {
+    /// Name as registered
+    name: String,
+    /// Salted password hash
+    hash: String,
+}
+
+/// Connected session
+struct Session {
+    addr: Recipient<session::Message>,
+    transport: Transport,
+    /// address session connects from, if known
+    peer: Option<IpAddr>,
+    /// flood protection, `None` if messages aren't rate limited
+    limiter: Option<Limiter>,
+}
+
+/// What failed password attempts count against. Not the session, it can
+/// just reconnect.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Guessed {
+    /// Account, by lower case name
+    Account(String),
+    /// Password protected room
+    Room(String),
+    /// Address sessions connect from, whichever password they guess
+    Peer(IpAddr),
+}
+
+/// Failed password attempts in a row
+struct Failures {
+    count: u32,
+    /// next attempt is refused before then
+    until: Instant,
+}
+
+/// ChatServer manages chat rooms and responsible for coordinating chat session. implementation is super primitive
+pub struct ChatServer {
+    sessions: HashMap<usize, Session>,
+    /// nicknames sessions picked, unique ignoring case
+    names: HashMap<usize, String>,
+    /// registered users, by lower case name
+    accounts: HashMap<String, Account>,
+    /// sessions logged in to an account, their nickname is the account name
+    users: HashSet<usize>,
+    rooms: HashMap<String, Room>,
+    session_ids: SessionIds,
+    /// id of the last message sent
+    last_message_id: u64,
+    /// durable log of rooms and messages, state is memory only without it
+    storage: Option<Storage>,
+    /// clean up of empty rooms
+    gc: RoomGc,
+    /// how fast sessions may send messages, unlimited if `None`
+    rate_limit: Option<RateLimit>,
+    /// failed password attempts, by account and by address
+    failures: HashMap<Guessed, Failures>,
+}
+
+impl Default for ChatServer {
+    fn default() -> Self {
+        // default room
+        let mut rooms = HashMap::new();
+        rooms.insert("Main".to_owned(), Room::default());
+
+        ChatServer {
+            sessions: HashMap::new(),
+            names: HashMap::new(),
+            accounts: HashMap::new(),
+            users: HashSet::new(),
+            rooms,
+            session_ids: SessionIds::new(),
+            last_message_id: 0,
+            storage: None,
+            gc: RoomGc::default(),
+            rate_limit: None,
+            failures: HashMap::new(),
+        }
+    }
+}
+
+impl ChatServer {
+    /// Chat server keeping rooms and messages in the log at `path`, restoring
+    /// whatever the log holds from previous runs. Empty rooms are cleaned up
+    /// according to `gc`, messages are limited by `rate_limit`. Account
+    /// `main_owner` owns "Main", if it is registered.
+    pub fn open(
+        path: impl AsRef<Path>,
+        gc: RoomGc,
+        rate_limit: Option<RateLimit>,
+        main_owner: Option<&str>,
+    ) -> io::Result<ChatServer> {
+        let (mut storage, records) = Storage::open(path)?;
+        let mut server = ChatServer::default();
+        for name in &gc.persistent {
+            server.rooms.entry(name.clone()).or_default();
+        }
+        server.gc = gc;
+        server.rate_limit = rate_limit;
+
+        for record in records {
+            match record {
+                Record::Room(name) => {
+                    server.rooms.entry(name).or_default();
+                }
+                Record::RoomRemoved(name) => {
+                    if !server.is_persistent(&name) {
+                        server.rooms.remove(&name);
+                    }
+                }
+                Record::Created { room, timestamp } => {
+                    server.rooms.entry(room).or_default().created = timestamp;
+                }
+                Record::Topic { room, topic } => {
+                    server.rooms.entry(room).or_default().topic = topic;
+                }
+                Record::Description { room, description } => {
+                    server.rooms.entry(room).or_default().description = description;
+                }
+                Record::Account { name, hash } => {
+                    server
+                        .accounts
+                        .insert(name.to_lowercase(), Account { name, hash });
+                }
+                Record::Owner { room, user } => {
+                    server.rooms.entry(room).or_default().owner = Some(Identity::Account(user));
+                }
+                Record::Moderator { room, user } => {
+                    let room = server.rooms.entry(room).or_default();
+                    room.moderators.insert(Identity::Account(user));
+                }
+                Record::ModeratorRemoved { room, user } => {
+                    if let Some(room) = server.rooms.get_mut(&room) {
+                        room.moderators.remove(&Identity::Account(user));
+                    }
+                }
+                Record::Ban { room, user } => {
+                    let room = server.rooms.entry(room).or_default();
+                    room.banned.insert(Identity::Account(user));
+                }
+                Record::Unban { room, user } => {
+                    if let Some(room) = server.rooms.get_mut(&room) {
+                        room.banned.remove(&Identity::Account(user));
+                    }
+                }
+                Record::Invite { room, user } => {
+                    let room = server.rooms.entry(room).or_default();
+                    room.invited.insert(Identity::Account(user));
+                }
+                Record::Visibility { room, visibility } => {
+                    server.rooms.entry(room).or_default().visibility = visibility;
+                }
+                Record::Password { room, hash } => {
+                    server.rooms.entry(room).or_default().password = hash;
+                }
+                Record::LastMessageId(id) => {
+                    server.last_message_id = server.last_message_id.max(id);
+                }
+                Record::Message(message) => {
+                    server.last_message_id = server.last_message_id.max(message.id);
+                    server
+                        .rooms
+                        .entry(message.room.clone())
+                        .or_default()
+                        .record(message);
+                }
+            }
+        }
+        println!("Restored {} rooms", server.rooms.len());
+
+        // whoever registers the name later must not get the room with it
+        let main_owner = main_owner.map(str::to_lowercase);
+        let main_owner = match main_owner {
+            Some(user) if server.accounts.contains_key(&user) => Some(Identity::Account(user)),
+            Some(user) => {
+                println!("No account {}, Main has no owner", user);
+                None
+            }
+            None => None,
+        };
+        server.rooms.get_mut("Main").unwrap().owner = main_owner;
+
+        // drop messages that fell out of history
+        storage.compact(&server.records())?;
+        server.storage = Some(storage);
+        Ok(server)
+    }
+
+    /// Current durable state, as log records
+    fn records(&self) -> Vec<Record> {
+        // ids of messages no longer kept must not be handed out again
+        let mut records = vec![Record::LastMessageId(self.last_message_id)];
+        records.extend(self.accounts.values().map(|account| Record::Account {
+            name: account.name.clone(),
+            hash: account.hash.clone(),
+        }));
+        for (name, room) in &self.rooms {
+            records.push(Record::Room(name.clone()));
+            records.push(Record::Created {
+                room: name.clone(),
+                timestamp: room.created,
+            });
+            if room.topic.is_some() {
+                records.push(Record::Topic {
+                    room: name.clone(),
+                    topic: room.topic.clone(),
+                });
+            }
+            if room.description.is_some() {
+                records.push(Record::Description {
+                    room: name.clone(),
+                    description: room.description.clone(),
+                });
+            }
+            // roles of guests don't outlive their sessions
+            if let Some(user) = room.owner.as_ref().and_then(Identity::account) {
+                records.push(Record::Owner {
+                    room: name.clone(),
+                    user: user.to_owned(),
+                });
+            }
+            for user in room.moderators.iter().filter_map(Identity::account) {
+                records.push(Record::Moderator {
+                    room: name.clone(),
+                    user: user.to_owned(),
+                });
+            }
+            for user in room.banned.iter().filter_map(Identity::account) {
+                records.push(Record::Ban {
+                    room: name.clone(),
+                    user: user.to_owned(),
+                });
+            }
+            for user in room.invited.iter().filter_map(Identity::account) {
+                records.push(Record::Invite {
+                    room: name.clone(),
+                    user: user.to_owned(),
+                });
+            }
+            if room.visibility != Visibility::Public {
+                records.push(Record::Visibility {
+                    room: name.clone(),
+                    visibility: room.visibility,
+                });
+            }
+            if room.password.is_some() {
+                records.push(Record::Password {
+                    room: name.clone(),
+                    hash: room.password.clone(),
+                });
+            }
+            records.extend(room.history.iter().cloned().map(Record::Message));
+        }
+        records
+    }
+
+    /// Rewrite the log from current state, so edited and deleted messages are
+    /// gone from disk too, not just from history
+    fn scrub(&mut self) {
+        let records = self.records();
+        if let Some(storage) = &mut self.storage {
+            if let Err(e) = storage.compact(&records) {
+                println!("Could not rewrite log: {}", e);
+            }
+        }
+    }
+
+    /// Append record to the log, if there is one. Chat keeps going when the
+    /// write fails, the change just won't survive a restart.
+    fn persist(&mut self, record: Record) {
+        if let Some(storage) = &mut self.storage {
+            if let Err(e) = storage.append(&record) {
+                println!("Could not persist {:?}: {}", record, e);
+            }
+        }
+    }
+
+    /// Wrap `body` into a new message for `room`, stamped with id and time
+    fn envelope(
+        &mut self,
+        room: &str,
+        sender: Option<usize>,
+        sender_name: Option<String>,
+        body: &str,
+    ) -> ChatMessage {
+        let (id, timestamp) = self.stamp();
+        let sender_account = sender
+            .map(|id| self.identity(id))
+            .and_then(|who| who.account().map(str::to_owned));
+
+        ChatMessage {
+            id,
+            room: room.to_owned(),
+            sender,
+            sender_name,
+            sender_account,
+            timestamp,
+            body: body.to_owned(),
+            edited: None,
+        }
+    }
+
+    /// Next message id and current server time
+    fn stamp(&mut self) -> (u64, u64) {
+        self.last_message_id += 1;
+        (self.last_message_id, now())
+    }
+
+    /// Charge session `id` for sending `body`, refusing it once the session
+    /// is over the rate limit for long enough. Requests other than messages
+    /// are charged too, with whatever text they carry as body.
+    fn throttle(&mut self, id: usize, body: &str) -> Result<(), RoomError> {
+        let limiter = self.sessions.get_mut(&id).and_then(|s| s.limiter.as_mut());
+        let verdict = match (&self.rate_limit, limiter) {
+            (Some(limit), Some(limiter)) => limiter.check(limit, body.len(), Instant::now()),
+            _ => Verdict::Allow,
+        };
+        match verdict {
+            Verdict::Allow => Ok(()),
+            Verdict::Warn => {
+                let warning = ChatResponse::Error {
+                    code: ErrorCode::RateLimited,
+                    message: "sending too fast, slow down".to_owned(),
+                    request_id: None,
+                };
+                self.send_to(id, warning);
+                Ok(())
+            }
+            Verdict::Drop => Err(RoomError::RateLimited),
+            Verdict::Muted => Err(RoomError::FloodMuted),
+            Verdict::Disconnect => {
+                println!("Session {} keeps flooding, disconnecting", id);
+                Err(RoomError::Flooding)
+            }
+        }
+    }
+
+    /// What a password attempt of session `id` on `target` counts against
+    fn guessed(&self, id: usize, target: Guessed) -> Vec<Guessed> {
+        let peer = self.peer(id);
+        iter::once(target).chain(peer.map(Guessed::Peer)).collect()
+    }
+
+    /// Whether any of `guessed` waits out failed password attempts
+    fn backing_off(&self, guessed: &[Guessed]) -> bool {
+        let now = Instant::now();
+        guessed
+            .iter()
+            .any(|g| self.failures.get(g).is_some_and(|f| now < f.until))
+    }
+
+    /// Count a failed password attempt against `guessed`, every failure in a
+    /// row doubles the wait before the next attempt
+    fn failed(&mut self, guessed: Vec<Guessed>) {
+        let now = Instant::now();
+        self.failures.retain(|_, f| now < f.until + LOGIN_FORGIVE);
+        for g in guessed {
+            let failures = self.failures.entry(g).or_insert(Failures {
+                count: 0,
+                until: now,
+            });
+            let backoff = LOGIN_BACKOFF * 2u32.saturating_pow(failures.count.min(16));
+            failures.count += 1;
+            failures.until = now + backoff.min(MAX_LOGIN_BACKOFF);
+        }
+    }
+
+    /// Room holding message `id` in its history
+    fn message_room(&self, id: u64) -> Option<String> {
+        self.rooms
+            .iter()
+            .find(|(_, room)| room.history.iter().any(|m| m.id == id))
+            .map(|(name, _)| name.clone())
+    }
+
+    /// Whether session `id` wrote `message`, in this session or, logged in,
+    /// in an earlier one of the same account
+    fn is_author(&self, id: usize, message: &ChatMessage) -> bool {
+        if message.sender == Some(id) {
+            return true;
+        }
+        // names are taken over once their guest is gone, accounts are not
+        match (self.identity(id).account(), &message.sender_account) {
+            (Some(user), Some(account)) => user == account,
+            _ => false,
+        }
+    }
+
+    /// Session going by `name`, or the session `#id`
+    fn lookup(&self, name: &str) -> Option<usize> {
+        if let Some(id) = name.strip_prefix('#').and_then(|id| id.parse().ok()) {
+            if self.sessions.contains_key(&id) {
+                return Some(id);
+            }
+        }
+        let name = name.to_lowercase();
+        self.names
+            .iter()
+            .find(|(_, n)| n.to_lowercase() == name)
+            .map(|(id, _)| *id)
+    }
+
+    /// Address session `id` connects from, if known
+    fn peer(&self, id: usize) -> Option<IpAddr> {
+        self.sessions.get(&id).and_then(|session| session.peer)
+    }
+
+    /// Identity room roles, bans and mutes of session `id` are tied to
+    fn identity(&self, id: usize) -> Identity {
+        match self.names.get(&id) {
+            Some(name) if self.users.contains(&id) => Identity::Account(name.to_lowercase()),
+            _ => Identity::Guest(id),
+        }
+    }
+
+    /// Identity of the user going by `name`, and its session if connected.
+    /// Accounts can be named while nobody is logged in to them.
+    fn target(&self, name: &str) -> Option<(Identity, Option<usize>)> {
+        if let Some(id) = self.lookup(name) {
+            return Some((self.identity(id), Some(id)));
+        }
+        let key = name.to_lowercase();
+        if self.accounts.contains_key(&key) {
+            Some((Identity::Account(key), None))
+        } else {
+            None
+        }
+    }
+
+    /// Hand room roles, bans and mutes session `id` got as a guest over to
+    /// account `user` it just logged in to
+    fn adopt(&mut self, id: usize, user: &str) {
+        let guest = Identity::Guest(id);
+        let user = user.to_lowercase();
+        let account = Identity::Account(user.clone());
+        let mut records = Vec::new();
+
+        for (name, room) in &mut self.rooms {
+            if room.owner.as_ref() == Some(&guest) {
+                room.owner = Some(account.clone());
+                records.push(Record::Owner {
+                    room: name.clone(),
+                    user: user.clone(),
+                });
+            }
+            if room.moderators.remove(&guest) {
+                room.moderators.insert(account.clone());
+                records.push(Record::Moderator {
+                    room: name.clone(),
+                    user: user.clone(),
+                });
+            }
+            if room.banned.remove(&guest) {
+                room.banned.insert(account.clone());
+                records.push(Record::Ban {
+                    room: name.clone(),
+                    user: user.clone(),
+                });
+            }
+            if room.muted.remove(&guest) {
+                room.muted.insert(account.clone());
+            }
+            if room.invited.remove(&guest) {
+                room.invited.insert(account.clone());
+                records.push(Record::Invite {
+                    room: name.clone(),
+                    user: user.clone(),
+                });
+            }
+        }
+        for record in records {
+            self.persist(record);
+        }
+    }
+
+    /// Remove session from room on behalf of a moderator, telling the room
+    /// and the session itself
+    fn kick(&mut self, room: &str, session: usize, banned: bool) {
+        let event = ChatResponse::Kicked {
+            room: room.to_owned(),
+            session,
+            name: self.names.get(&session).cloned(),
+            banned,
+        };
+        self.send_room(room, event, None);
+        if let Some(room) = self.rooms.get_mut(room) {
+            room.sessions.remove(&session);
+        }
+    }
+
+    /// Give session a new nickname and tell everyone who can see it
+    fn rename(&mut self, id: usize, name: String) {
+        let old_name = self.names.insert(id, name.clone());
+        let event = ChatResponse::Renamed {
+            session: id,
+            old_name,
+            new_name: name,
+        };
+        for mate in self.room_mates(id) {
+            self.send_to(mate, event.clone());
+        }
+    }
+
+    /// Session itself and everyone sharing a room with it
+    fn room_mates(&self, id: usize) -> HashSet<usize> {
+        let mut mates: HashSet<usize> = self
+            .rooms
+            .values()
+            .filter(|room| room.sessions.contains(&id))
+            .flat_map(|room| room.sessions.iter().copied())
+            .collect();
+        mates.insert(id);
+        mates
+    }
+
+    /// Send message or event to all users in the room, except `skip`
+    fn send_room(&self, room: &str, response: ChatResponse, skip: Option<usize>) {
+        if let Some(room) = self.rooms.get(room) {
+            for id in &room.sessions {
+                if Some(*id) != skip {
+                    if let Some(session) = self.sessions.get(id) {
+                        let _ = session.addr.do_send(session::Message(response.clone()));
+                    }
+                }
+            }
+        }
+    }
+
+    /// Send message or event to one session
+    fn send_to(&self, id: usize, response: ChatResponse) {
+        if let Some(session) = self.sessions.get(&id) {
+            let _ = session.addr.do_send(session::Message(response));
+        }
+    }
+
+    /// Sessions that find `room` in their room list
+    fn viewers(&self, room: &Room) -> HashSet<usize> {
+        self.sessions
+            .keys()
+            .copied()
+            .filter(|id| room.is_visible_to(*id, &self.identity(*id)))
+            .collect()
+    }
+
+    /// Send room list event to every session that can see `room`
+    fn send_listing(&self, room: &Room, event: ChatResponse) {
+        for id in self.viewers(room) {
+            self.send_to(id, event.clone());
+        }
+    }
+
+    /// "Main" and configured rooms are never garbage collected
+    fn is_persistent(&self, room: &str) -> bool {
+        room == "Main" || self.gc.persistent.contains(room)
+    }
+
+    /// Remove rooms that stayed empty for longer than `gc.idle`, unless they
+    /// have to be kept
+    fn collect_rooms(&mut self, idle: Duration) {
+        let now = Instant::now();
+        let mut removed = Vec::new();
+
+        for (name, room) in &mut self.rooms {
+            if !room.sessions.is_empty() || room.is_kept() {
+                room.empty_since = None;
+                continue;
+            }
+            let empty_since = *room.empty_since.get_or_insert(now);
+            if now.duration_since(empty_since) >= idle {
+                removed.push(name.clone());
+            }
+        }
+
+        for name in removed {
+            if self.is_persistent(&name) {
+                continue;
+            }
+            println!("Removing empty room {}", name);
+            if let Some(room) = self.rooms.remove(&name) {
+                self.persist(Record::RoomRemoved(name.clone()));
+                self.send_listing(&room, ChatResponse::RoomRemoved { room: name });
+            }
+        }
+    }
+}
+
+/// Make actor from ChatServer
+impl Actor for ChatServer {
+    /// we are going to use simple context we just need ability to communicate
+    /// with other actors
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        if let Some(idle) = self.gc.idle {
+            ctx.run_interval(GC_INTERVAL, move |act, _| act.collect_rooms(idle));
+        }
+    }
+}
+
+/// Handler for Connect message.
+///
+/// Register new session and assign unique id to this session
+impl Handler<Connect> for ChatServer {
+    type Result = usize;
+
+    fn handle(&mut self, msg: Connect, _: &mut Self::Context) -> Self::Result {
+        println!("Someone joined ");
+
+        // register session with random id
+        let id = self.session_ids.allocate(&self.sessions);
+        self.sessions.insert(
+            id,
+            Session {
+                addr: msg.addr,
+                transport: msg.transport,
+                peer: msg.peer,
+                limiter: self.rate_limit.as_ref().map(Limiter::new),
+            },
+        );
+
+        // guests banned from Main by address stay out of it
+        let main = self.rooms.get_mut("Main").unwrap();
+        if main.is_banned(&Identity::Guest(id), msg.peer) {
+            let kicked = ChatResponse::Kicked {
+                room: "Main".to_owned(),
+                session: id,
+                name: None,
+                banned: true,
+            };
+            self.send_to(id, kicked);
+            return id;
+        }
+
+        // auto join session to Main room and notify all users in it
+        main.sessions.insert(id);
+        let event = ChatResponse::UserJoined {
+            room: "Main".to_owned(),
+            session: id,
+            name: None,
+        };
+        self.send_room("Main", event, Some(id));
+
+        // send id back
+        id
+    }
+}
+
+/// Handler for Disconnect message.
+impl Handler<Disconnect> for ChatServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: Disconnect, _: &mut Self::Context) -> Self::Result {
+        println!("Someone disconnected");
+
+        let mut rooms: Vec<String> = Vec::new();
+
+        let guest = Identity::Guest(msg.id);
+        let name = self.names.remove(&msg.id);
+        self.users.remove(&msg.id);
+
+        // remove address
+        if self.sessions.remove(&msg.id).is_some() {
+            // remove session from all roms
+            for (name, room) in &mut self.rooms {
+                if room.sessions.remove(&msg.id) {
+                    rooms.push(name.to_owned());
+                }
+                // whatever the session got as a guest goes with it
+                if room.owner.as_ref() == Some(&guest) {
+                    room.owner = None;
+                }
+                room.moderators.remove(&guest);
+                room.banned.remove(&guest);
+                room.muted.remove(&guest);
+                room.invited.remove(&guest);
+            }
+        }
+        // send message to other users
+        for room in rooms {
+            let event = ChatResponse::UserDisconnected {
+                room: room.clone(),
+                session: msg.id,
+                name: name.clone(),
+            };
+            self.send_room(&room, event, None);
+        }
+    }
+}
+
+/// Handler for Message message
+impl Handler<Message> for ChatServer {
+    type Result = Result<u64, RoomError>;
+    fn handle(&mut self, msg: Message, _: &mut Self::Context) -> Self::Result {
+        if msg.msg.len() > MAX_MESSAGE_LENGTH {
+            return Err(RoomError::MessageTooLong);
+        }
+        self.throttle(msg.id, &msg.msg)?;
+        let who = self.identity(msg.id);
+        match self.rooms.get(&msg.room) {
+            None => return Err(RoomError::NoSuchRoom),
+            Some(room) if !room.sessions.contains(&msg.id) => return Err(RoomError::NotInRoom),
+            Some(room) if room.muted.contains(&who) => return Err(RoomError::Muted),
+            Some(_) => (),
+        }
+
+        // name comes from the server, peers can't pass themselves off as someone else
+        let name = self.names.get(&msg.id).cloned();
+        let message = self.envelope(&msg.room, Some(msg.id), name, &msg.msg);
+        let id = message.id;
+        let response = ChatResponse::Message(message.clone());
+        self.send_room(&msg.room, response, Some(msg.id));
+        if let Some(room) = self.rooms.get_mut(&msg.room) {
+            room.record(message.clone());
+            self.persist(Record::Message(message));
+        }
+        Ok(id)
+    }
+}
+
+/// Handler for EditMessage message, members of the room get the edited
+/// message. Authors have to be in the room and allowed to talk there, like
+/// for sending it.
+impl Handler<EditMessage> for ChatServer {
+    type Result = Result<(), RoomError>;
+
+    fn handle(&mut self, msg: EditMessage, _: &mut Self::Context) -> Self::Result {
+        let EditMessage { id, message, body } = msg;
+
+        if body.len() > MAX_MESSAGE_LENGTH {
+            return Err(RoomError::MessageTooLong);
+        }
+        self.throttle(id, &body)?;
+        let name = self.message_room(message).ok_or(RoomError::NoSuchMessage)?;
+        let who = self.identity(id);
+        let room = &self.rooms[&name];
+        // banned authors can't tell their messages are still there
+        if !room.is_readable_by(id, &who, self.peer(id)) {
+            return Err(RoomError::NoSuchMessage);
+        }
+        let original = room.history.iter().find(|m| m.id == message);
+        if !original.is_some_and(|m| self.is_author(id, m)) {
+            return Err(RoomError::PermissionDenied);
+        }
+        if !room.sessions.contains(&id) {
+            return Err(RoomError::NotInRoom);
+        }
+        if room.muted.contains(&who) {
+            return Err(RoomError::Muted);
+        }
+
+        let edited = {
+            let message = self
+                .rooms
+                .get_mut(&name)
+                .and_then(|room| room.message_mut(message))
+                .ok_or(RoomError::NoSuchMessage)?;
+            message.body = body;
+            message.edited = Some(now());
+            message.clone()
+        };
+        self.send_room(&name, ChatResponse::MessageEdited(edited), None);
+        self.scrub();
+        Ok(())
+    }
+}
+
+/// Handler for DeleteMessage message, members of the room are told
+impl Handler<DeleteMessage> for ChatServer {
+    type Result = Result<(), RoomError>;
+
+    fn handle(&mut self, msg: DeleteMessage, _: &mut Self::Context) -> Self::Result {
+        let DeleteMessage { id, message } = msg;
+
+        let name = self.message_room(message).ok_or(RoomError::NoSuchMessage)?;
+        let who = self.identity(id);
+        let room = &self.rooms[&name];
+        let author = room
+            .history
+            .iter()
+            .find(|m| m.id == message)
+            .is_some_and(|m| self.is_author(id, m));
+        if !author {
+            if !room.is_visible_to(id, &who) {
+                return Err(RoomError::NoSuchMessage);
+            }
+            if room.role(&who) == Role::Member {
+                return Err(RoomError::PermissionDenied);
+            }
+        }
+
+        if let Some(room) = self.rooms.get_mut(&name) {
+            room.history.retain(|m| m.id != message);
+        }
+        let event = ChatResponse::MessageDeleted {
+            room: name.clone(),
+            id: message,
+            session: id,
+            name: self.names.get(&id).cloned(),
+        };
+        self.send_room(&name, event, None);
+        self.scrub();
+        Ok(())
+    }
+}
+
+/// Handler for Direct message
+impl Handler<Direct> for ChatServer {
+    type Result = Result<(), RoomError>;
+
+    fn handle(&mut self, msg: Direct, _: &mut Self::Context) -> Self::Result {
+        if msg.msg.len() > MAX_MESSAGE_LENGTH {
+            return Err(RoomError::MessageTooLong);
+        }
+        self.throttle(msg.id, &msg.msg)?;
+        let recipient = self.lookup(&msg.to).ok_or(RoomError::NoSuchUser)?;
+
+        let (id, timestamp) = self.stamp();
+        // direct messages aren't logged, their ids have to be
+        self.persist(Record::LastMessageId(id));
+        let message = ChatResponse::DirectMessage(DirectMessage {
+            id,
+            sender: msg.id,
+            sender_name: self.names.get(&msg.id).cloned(),
+            timestamp,
+            body: msg.msg,
+        });
+        self.send_to(recipient, message);
+        Ok(())
+    }
+}
+
+/// Handler for SetName message, tells everyone who can see the session
+impl Handler<SetName> for ChatServer {
+    type Result = Result<(), NameError>;
+
+    fn handle(&mut self, msg: SetName, _: &mut Self::Context) -> Self::Result {
+        let SetName { id, name } = msg;
+
+        self.throttle(id, &name).map_err(NameError::Throttled)?;
+        if self.users.contains(&id) {
+            return Err(NameError::LoggedIn);
+        }
+        if !valid_name(&name) {
+            return Err(NameError::Invalid);
+        }
+        // guests can't pass themselves off as registered users
+        if self.accounts.contains_key(&name.to_lowercase()) {
+            return Err(NameError::Taken);
+        }
+        match self.lookup(&name) {
+            Some(owner) if owner != id => return Err(NameError::Taken),
+            _ => (),
+        }
+
+        self.rename(id, name);
+        Ok(())
+    }
+}
+
+/// Handler for Register message
+impl Handler<Register> for ChatServer {
+    type Result = Result<(), AccountError>;
+
+    fn handle(&mut self, msg: Register, _: &mut Self::Context) -> Self::Result {
+        let Register { id, user, hash } = msg;
+
+        if self.users.contains(&id) {
+            return Err(AccountError::AlreadyLoggedIn);
+        }
+        let key = user.to_lowercase();
+        if self.accounts.contains_key(&key) {
+            return Err(AccountError::Taken);
+        }
+        match self.lookup(&user) {
+            Some(owner) if owner != id => return Err(AccountError::Taken),
+            _ => (),
+        }
+
+        println!("Registered user {}", user);
+        self.persist(Record::Account {
+            name: user.clone(),
+            hash: hash.clone(),
+        });
+        self.accounts.insert(
+            key,
+            Account {
+                name: user.clone(),
+                hash,
+            },
+        );
+        self.users.insert(id);
+        self.adopt(id, &user);
+        self.rename(id, user);
+        Ok(())
+    }
+}
+
+/// Handler for AttemptLogin message, refused while the session waits out
+/// failed attempts
+impl Handler<AttemptLogin> for ChatServer {
+    type Result = Result<(), AccountError>;
+
+    fn handle(&mut self, msg: AttemptLogin, _: &mut Self::Context) -> Self::Result {
+        self.throttle(msg.id, &msg.user)
+            .map_err(AccountError::Throttled)?;
+        let guessed = self.guessed(msg.id, Guessed::Account(msg.user.to_lowercase()));
+        if self.backing_off(&guessed) {
+            return Err(AccountError::TooManyAttempts);
+        }
+        Ok(())
+    }
+}
+
+/// Handler for LoginAttempted message
+impl Handler<LoginAttempted> for ChatServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: LoginAttempted, _: &mut Self::Context) -> Self::Result {
+        let account = Guessed::Account(msg.user.to_lowercase());
+        if msg.succeeded {
+            // others sharing the address may still be guessing
+            self.failures.remove(&account);
+        } else {
+            let guessed = self.guessed(msg.id, account);
+            self.failed(guessed);
+        }
+    }
+}
+
+/// Handler for AttemptJoin message, refused while the room or the address of
+/// the session waits out wrong passwords
+impl Handler<AttemptJoin> for ChatServer {
+    type Result = Result<(), RoomError>;
+
+    fn handle(&mut self, msg: AttemptJoin, _: &mut Self::Context) -> Self::Result {
+        let guessed = self.guessed(msg.id, Guessed::Room(msg.room));
+        if self.backing_off(&guessed) {
+            return Err(RoomError::TooManyAttempts);
+        }
+        Ok(())
+    }
+}
+
+/// Handler for JoinAttempted message
+impl Handler<JoinAttempted> for ChatServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: JoinAttempted, _: &mut Self::Context) -> Self::Result {
+        let room = Guessed::Room(msg.room);
+        if msg.succeeded {
+            self.failures.remove(&room);
+        } else {
+            let guessed = self.guessed(msg.id, room);
+            self.failed(guessed);
+        }
+    }
+}
+
+/// Handler for PasswordHash message
+impl Handler<PasswordHash> for ChatServer {
+    type Result = Option<String>;
+
+    fn handle(&mut self, msg: PasswordHash, _: &mut Self::Context) -> Self::Result {
+        self.accounts
+            .get(&msg.user.to_lowercase())
+            .map(|account| account.hash.clone())
+    }
+}
+
+/// Handler for Login message, session takes the name the account was
+/// registered with
+impl Handler<Login> for ChatServer {
+    type Result = Result<String, AccountError>;
+
+    fn handle(&mut self, msg: Login, _: &mut Self::Context) -> Self::Result {
+        let Login { id, user } = msg;
+
+        if self.users.contains(&id) {
+            return Err(AccountError::AlreadyLoggedIn);
+        }
+        let name = match self.accounts.get(&user.to_lowercase()) {
+            Some(account) => account.name.clone(),
+            None => return Err(AccountError::InvalidCredentials),
+        };
+        // guests can't take account names, whoever has it logged in elsewhere
+        if self.lookup(&name).is_some() {
+            return Err(AccountError::AlreadyLoggedIn);
+        }
+
+        println!("User {} logged in", name);
+        self.users.insert(id);
+        self.adopt(id, &name);
+        self.rename(id, name.clone());
+        Ok(name)
+    }
+}
+
+/// Handler for Who message, members are sorted by name, unnamed ones last
+impl Handler<Who> for ChatServer {
+    type Result = MessageResult<Who>;
+
+    fn handle(&mut self, msg: Who, _: &mut Self::Context) -> Self::Result {
+        let who = self.identity(msg.id);
+        let peer = self.peer(msg.id);
+        let room = match self.rooms.get(&msg.room) {
+            Some(room) if room.is_readable_by(msg.id, &who, peer) => room,
+            _ => return MessageResult(None),
+        };
+
+        let mut members: Vec<Member> = room
+            .sessions
+            .iter()
+            .filter_map(|id| {
+                self.sessions.get(id).map(|session| Member {
+                    session: *id,
+                    name: self.names.get(id).cloned(),
+                    transport: session.transport,
+                })
+            })
+            .collect();
+        members.sort_by(|a, b| match (&a.name, &b.name) {
+            (Some(a), Some(b)) => a.to_lowercase().cmp(&b.to_lowercase()),
+            (Some(_), None) => Ordering::Less,
+            (None, Some(_)) => Ordering::Greater,
+            (None, None) => a.session.cmp(&b.session),
+        });
+        MessageResult(Some(members))
+    }
+}
+
+/// Handler for ListRooms message, rooms are sorted by name
+impl Handler<ListRooms> for ChatServer {
+    type Result = MessageResult<ListRooms>;
+
+    fn handle(&mut self, msg: ListRooms, _: &mut Self::Context) -> Self::Result {
+        let who = self.identity(msg.id);
+        let mut rooms = Vec::new();
+
+        for (key, room) in &self.rooms {
+            if room.is_visible_to(msg.id, &who) {
+                rooms.push(room.info(key))
+            }
+        }
+        rooms.sort_by(|a, b| a.name.cmp(&b.name));
+        MessageResult(rooms)
+    }
+}
+
+/// Join room, send join event to new room.
+/// Session stays member of rooms it joined before.
+impl Handler<Join> for ChatServer {
+    type Result = Result<(), RoomError>;
+
+    fn handle(&mut self, msg: Join, _: &mut Self::Context) -> Self::Result {
+        let Join {
+            id,
+            name,
+            password_ok,
+            hash,
+            visibility,
+        } = msg;
+        let who = self.identity(id);
+        let peer = self.peer(id);
+
+        match self.rooms.get(&name) {
+            Some(room) if room.is_banned(&who, peer) => return Err(RoomError::Banned),
+            Some(room) if room.sessions.contains(&id) || room.is_invited(&who) => (),
+            Some(room) if room.visibility == Visibility::InviteOnly => {
+                return Err(RoomError::NotInvited)
+            }
+            Some(room) if room.password.is_some() && !password_ok => {
+                return Err(RoomError::WrongPassword)
+            }
+            Some(_) => (),
+            None => {
+                let room = Room {
+                    owner: Some(who.clone()),
+                    visibility,
+                    password: hash.clone(),
+                    ..Room::default()
+                };
+                self.persist(Record::Room(name.clone()));
+                self.persist(Record::Created {
+                    room: name.clone(),
+                    timestamp: room.created,
+                });
+                if let Some(user) = who.account() {
+                    self.persist(Record::Owner {
+                        room: name.clone(),
+                        user: user.to_owned(),
+                    });
+                }
+                if visibility != Visibility::Public {
+                    self.persist(Record::Visibility {
+                        room: name.clone(),
+                        visibility,
+                    });
+                }
+                if hash.is_some() {
+                    self.persist(Record::Password {
+                        room: name.clone(),
+                        hash,
+                    });
+                }
+                // hidden rooms are announced to their creator only
+                self.send_listing(&room, ChatResponse::RoomCreated { room: name.clone() });
+                self.rooms.insert(name.clone(), room);
+            }
+        }
+        if self.rooms.get_mut(&name).unwrap().sessions.insert(id) {
+            let event = ChatResponse::UserJoined {
+                room: name.clone(),
+                session: id,
+                name: self.names.get(&id).cloned(),
+            };
+            self.send_room(&name, event, Some(id));
+        }
+        Ok(())
+    }
+}
+
+/// Leave room, send leave event to the room
+impl Handler<Leave> for ChatServer {
+    type Result = Result<(), RoomError>;
+
+    fn handle(&mut self, msg: Leave, _: &mut Self::Context) -> Self::Result {
+        let Leave { id, name } = msg;
+
+        self.throttle(id, &name)?;
+        let left = match self.rooms.get_mut(&name) {
+            Some(room) => room.sessions.remove(&id),
+            None => false,
+        };
+        if left {
+            let event = ChatResponse::UserLeft {
+                room: name.clone(),
+                session: id,
+                name: self.names.get(&id).cloned(),
+            };
+            self.send_room(&name, event, None);
+        }
+        Ok(())
+    }
+}
+
+/// Handler for Moderate message
+impl Handler<Moderate> for ChatServer {
+    type Result = Result<(), RoomError>;
+
+    fn handle(&mut self, msg: Moderate, _: &mut Self::Context) -> Self::Result {
+        let Moderate {
+            id,
+            room: name,
+            user,
+            action,
+        } = msg;
+
+        let (target, session) = self.target(&user).ok_or(RoomError::NoSuchUser)?;
+        let actor = self.identity(id);
+        let room = self.rooms.get(&name).ok_or(RoomError::NoSuchRoom)?;
+        let allowed = match action {
+            Moderation::AddModerator | Moderation::RemoveModerator => {
+                room.role(&actor) == Role::Owner && target != actor
+            }
+            _ => room.role(&actor) > room.role(&target),
+        };
+        if !allowed {
+            return Err(RoomError::PermissionDenied);
+        }
+        let member = session.filter(|session| room.sessions.contains(session));
+        // guests are banned by address too, so they can't reconnect as
+        // another guest
+        let peer = match target {
+            Identity::Guest(session) => self.peer(session),
+            Identity::Account(_) => None,
+        };
+
+        let room = self.rooms.get_mut(&name).unwrap();
+        match action {
+            Moderation::Kick => {
+                let session = member.ok_or(RoomError::NotInRoom)?;
+                self.kick(&name, session, false);
+            }
+            Moderation::Ban => {
+                room.banned.insert(target.clone());
+                room.banned_peers.extend(peer);
+                if let Some(user) = target.account() {
+                    self.persist(Record::Ban {
+                        room: name.clone(),
+                        user: user.to_owned(),
+                    });
+                }
+                if let Some(session) = member {
+                    self.kick(&name, session, true);
+                }
+            }
+            Moderation::Unban => {
+                if let Some(peer) = peer {
+                    room.banned_peers.remove(&peer);
+                }
+                if room.banned.remove(&target) {
+                    if let Some(user) = target.account() {
+                        self.persist(Record::Unban {
+                            room: name,
+                            user: user.to_owned(),
+                        });
+                    }
+                }
+            }
+            Moderation::Mute | Moderation::Unmute => {
+                let muted = action == Moderation::Mute;
+                let changed = if muted {
+                    room.muted.insert(target)
+                } else {
+                    room.muted.remove(&target)
+                };
+                if let (true, Some(session)) = (changed, session) {
+                    let event = ChatResponse::Muted {
+                        room: name.clone(),
+                        session,
+                        name: self.names.get(&session).cloned(),
+                        muted,
+                    };
+                    self.send_room(&name, event.clone(), Some(session));
+                    self.send_to(session, event);
+                }
+            }
+            Moderation::Invite => {
+                if room.invited.insert(target.clone()) {
+                    if let Some(user) = target.account() {
+                        self.persist(Record::Invite {
+                            room: name.clone(),
+                            user: user.to_owned(),
+                        });
+                    }
+                }
+                if let Some(session) = session {
+                    let event = ChatResponse::Invited {
+                        room: name,
+                        session: id,
+                        name: self.names.get(&id).cloned(),
+                    };
+                    self.send_to(session, event);
+                }
+            }
+            Moderation::AddModerator => {
+                if room.moderators.insert(target.clone()) {
+                    if let Some(user) = target.account() {
+                        self.persist(Record::Moderator {
+                            room: name,
+                            user: user.to_owned(),
+                        });
+                    }
+                }
+            }
+            Moderation::RemoveModerator => {
+                if room.moderators.remove(&target) {
+                    if let Some(user) = target.account() {
+                        self.persist(Record::ModeratorRemoved {
+                            room: name,
+                            user: user.to_owned(),
+                        });
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Handler for RoomPassword message
+impl Handler<RoomPassword> for ChatServer {
+    type Result = Result<Option<Option<String>>, RoomError>;
+
+    fn handle(&mut self, msg: RoomPassword, _: &mut Self::Context) -> Self::Result {
+        self.throttle(msg.id, &msg.room)?;
+        Ok(self.rooms.get(&msg.room).map(|room| room.password.clone()))
+    }
+}
+
+/// Handler for SetVisibility message, sessions that can no longer see the room
+/// are told it was removed, sessions that now can are told it was created
+impl Handler<SetVisibility> for ChatServer {
+    type Result = Result<(), RoomError>;
+
+    fn handle(&mut self, msg: SetVisibility, _: &mut Self::Context) -> Self::Result {
+        let SetVisibility {
+            id,
+            room: name,
+            visibility,
+        } = msg;
+
+        let who = self.identity(id);
+        let room = self.rooms.get(&name).ok_or(RoomError::NoSuchRoom)?;
+        if room.role(&who) != Role::Owner {
+            return Err(RoomError::PermissionDenied);
+        }
+        let before = self.viewers(room);
+
+        self.rooms.get_mut(&name).unwrap().visibility = visibility;
+        self.persist(Record::Visibility {
+            room: name.clone(),
+            visibility,
+        });
+
+        let after = self.viewers(&self.rooms[&name]);
+        for id in before.difference(&after) {
+            self.send_to(*id, ChatResponse::RoomRemoved { room: name.clone() });
+        }
+        for id in after.difference(&before) {
+            self.send_to(*id, ChatResponse::RoomCreated { room: name.clone() });
+        }
+        Ok(())
+    }
+}
+
+/// Handler for SetPassword message
+impl Handler<SetPassword> for ChatServer {
+    type Result = Result<(), RoomError>;
+
+    fn handle(&mut self, msg: SetPassword, _: &mut Self::Context) -> Self::Result {
+        let SetPassword {
+            id,
+            room: name,
+            hash,
+        } = msg;
+
+        let who = self.identity(id);
+        let room = self.rooms.get_mut(&name).ok_or(RoomError::NoSuchRoom)?;
+        if room.role(&who) != Role::Owner {
+            return Err(RoomError::PermissionDenied);
+        }
+        room.password = hash.clone();
+        self.persist(Record::Password { room: name, hash });
+        Ok(())
+    }
+}
+
+/// Handler for SetTopic message
+impl Handler<SetTopic> for ChatServer {
+    type Result = Result<(), RoomError>;
+
+    fn handle(&mut self, msg: SetTopic, _: &mut Self::Context) -> Self::Result {
+        let SetTopic {
+            id,
+            room: name,
+            topic,
+        } = msg;
+
+        let topic = topic.filter(|topic| !topic.is_empty());
+        if topic.as_ref().map_or(0, |t| t.chars().count()) > MAX_TOPIC_LENGTH {
+            return Err(RoomError::TooLong);
+        }
+        let who = self.identity(id);
+        let room = self.rooms.get_mut(&name).ok_or(RoomError::NoSuchRoom)?;
+        if room.role(&who) == Role::Member {
+            return Err(RoomError::PermissionDenied);
+        }
+        room.topic = topic.clone();
+        self.persist(Record::Topic {
+            room: name.clone(),
+            topic: topic.clone(),
+        });
+
+        let event = ChatResponse::TopicChanged {
+            room: name.clone(),
+            session: id,
+            name: self.names.get(&id).cloned(),
+            topic,
+        };
+        self.send_room(&name, event, None);
+        Ok(())
+    }
+}
+
+/// Handler for SetDescription message
+impl Handler<SetDescription> for ChatServer {
+    type Result = Result<(), RoomError>;
+
+    fn handle(&mut self, msg: SetDescription, _: &mut Self::Context) -> Self::Result {
+        let SetDescription {
+            id,
+            room: name,
+            description,
+        } = msg;
+
+        let description = description.filter(|description| !description.is_empty());
+        if description.as_ref().map_or(0, |d| d.chars().count()) > MAX_DESCRIPTION_LENGTH {
+            return Err(RoomError::TooLong);
+        }
+        let who = self.identity(id);
+        let room = self.rooms.get_mut(&name).ok_or(RoomError::NoSuchRoom)?;
+        if room.role(&who) != Role::Owner {
+            return Err(RoomError::PermissionDenied);
+        }
+        room.description = description.clone();
+        self.persist(Record::Description {
+            room: name,
+            description,
+        });
+        Ok(())
+    }
+}
+
+/// Handler for History message
+impl Handler<History> for ChatServer {
+    type Result = MessageResult<History>;
+
+    fn handle(&mut self, msg: History, _: &mut Self::Context) -> Self::Result {
+        let History {
+            id,
+            room,
+            before,
+            limit,
+        } = msg;
+        let who = self.identity(id);
+        let peer = self.peer(id);
+        MessageResult(
+            self.rooms
+                .get(&room)
+                .filter(|room| room.is_readable_by(id, &who, peer))
+                .map(|room| room.history(before, limit)),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(id: u64, room: &str, body: &str) -> ChatMessage {
+        ChatMessage {
+            id,
+            room: room.to_owned(),
+            sender: Some(1),
+            sender_name: Some("bob".to_owned()),
+            sender_account: Some("bob".to_owned()),
+            timestamp: 1000 * id,
+            body: body.to_owned(),
+            edited: None,
+        }
+    }
+
+    fn bob() -> Identity {
+        Identity::Account("bob".to_owned())
+    }
+
+    /// Server without log, rooms stay in memory only
+    fn server(rooms: Vec<(&str, Room)>) -> ChatServer {
+        let mut server = ChatServer::default();
+        for (name, room) in rooms {
+            server.rooms.insert(name.to_owned(), room);
+        }
+        server
+    }
+
+    /// Stands in for a session, drops whatever the server sends
+    struct Sink;
+
+    impl Actor for Sink {
+        type Context = Context<Self>;
+    }
+
+    impl Handler<session::Message> for Sink {
+        type Result = ();
+
+        fn handle(&mut self, _: session::Message, _: &mut Self::Context) {}
+    }
+
+    /// Outcome of the second of two `request`s a session sends right away,
+    /// to a server letting sessions send one request at a time
+    fn second<M>(request: fn(usize) -> M) -> M::Result
+    where
+        M: actix::Message + Send + 'static,
+        M::Result: Send,
+        ChatServer: Handler<M>,
+    {
+        let mut server = server(vec![]);
+        server.rate_limit = Some(RateLimit {
+            messages_per_second: 0.001,
+            burst: 1000.0,
+            warnings: 0,
+            ..RateLimit::default()
+        });
+        System::new("test").block_on(async move {
+            let server = server.start();
+            let id = connect(&server).await;
+            server.send(request(id)).await.unwrap();
+            server.send(request(id)).await.unwrap()
+        })
+    }
+
+    /// Id of a new session of `server`
+    async fn connect(server: &Addr<ChatServer>) -> usize {
+        let connect = Connect {
+            addr: Sink.start().recipient(),
+            transport: Transport::Tcp,
+            peer: None,
+        };
+        server.send(connect).await.unwrap()
+    }
+
+    #[test]
+    fn renaming_is_rate_limited() {
+        let res = second(|id| SetName {
+            id,
+            name: "bob".to_owned(),
+        });
+        assert_eq!(res, Err(NameError::Throttled(RoomError::RateLimited)));
+    }
+
+    #[test]
+    fn joining_is_rate_limited() {
+        // first thing asked on every join, with or without password
+        let res = second(|id| RoomPassword {
+            id,
+            room: "HR".to_owned(),
+        });
+        assert_eq!(res, Err(RoomError::RateLimited));
+    }
+
+    #[test]
+    fn leaving_is_rate_limited() {
+        let res = second(|id| Leave {
+            id,
+            name: "Main".to_owned(),
+        });
+        assert_eq!(res, Err(RoomError::RateLimited));
+    }
+
+    #[test]
+    fn logging_in_is_rate_limited() {
+        // registering asks the same before hashing the password
+        let res = second(|id| AttemptLogin {
+            id,
+            user: "bob".to_owned(),
+        });
+        assert_eq!(res, Err(AccountError::Throttled(RoomError::RateLimited)));
+    }
+
+    #[test]
+    fn open_replays_log() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("chat.log");
+        let (mut storage, _) = Storage::open(&path).unwrap();
+        let log = vec![
+            Record::Account {
+                name: "Bob".to_owned(),
+                hash: "hash".to_owned(),
+            },
+            Record::Room("HR".to_owned()),
+            Record::Owner {
+                room: "HR".to_owned(),
+                user: "bob".to_owned(),
+            },
+            Record::Ban {
+                room: "HR".to_owned(),
+                user: "eve".to_owned(),
+            },
+            Record::Unban {
+                room: "HR".to_owned(),
+                user: "eve".to_owned(),
+            },
+            Record::Visibility {
+                room: "HR".to_owned(),
+                visibility: Visibility::InviteOnly,
+            },
+            Record::Topic {
+                room: "HR".to_owned(),
+                topic: Some("payroll".to_owned()),
+            },
+            Record::Message(message(3, "HR", "hi")),
+            Record::Message(message(7, "HR", "there")),
+            Record::Room("gone".to_owned()),
+            Record::Message(message(8, "gone", "bye")),
+            Record::RoomRemoved("gone".to_owned()),
+        ];
+        for record in &log {
+            storage.append(record).unwrap();
+        }
+        drop(storage);
+
+        // later opens replay the log the first one compacted
+        for _ in 0..3 {
+            let server = ChatServer::open(&path, RoomGc::default(), None, Some("BOB")).unwrap();
+            // message 8 went with its room, its id stays used
+            assert_eq!(server.last_message_id, 8);
+            assert!(server.accounts.contains_key("bob"));
+            assert_eq!(server.rooms["Main"].owner, Some(bob()));
+            assert!(!server.rooms.contains_key("gone"));
+
+            let room = &server.rooms["HR"];
+            assert_eq!(room.owner, Some(bob()));
+            assert!(room.banned.is_empty());
+            assert_eq!(room.visibility, Visibility::InviteOnly);
+            assert_eq!(room.topic.as_deref(), Some("payroll"));
+            let ids: Vec<u64> = room.history.iter().map(|m| m.id).collect();
+            assert_eq!(ids, [3, 7]);
+        }
+    }
+
+    #[test]
+    fn gc_keeps_rooms_with_durable_settings() {
+        let mut server = server(vec![
+            ("temp", Room::default()),
+            (
+                "guest",
+                Room {
+                    owner: Some(Identity::Guest(1)),
+                    ..Room::default()
+                },
+            ),
+            (
+                "owned",
+                Room {
+                    owner: Some(bob()),
+                    ..Room::default()
+                },
+            ),
+            (
+                "unlisted",
+                Room {
+                    visibility: Visibility::Unlisted,
+                    ..Room::default()
+                },
+            ),
+            (
+                "locked",
+                Room {
+                    password: Some("hash".to_owned()),
+                    ..Room::default()
+                },
+            ),
+            ("configured", Room::default()),
+        ]);
+        server.gc.persistent.insert("configured".to_owned());
+        server.collect_rooms(Duration::from_secs(0));
+
+        let mut rooms: Vec<&str> = server.rooms.keys().map(String::as_str).collect();
+        rooms.sort_unstable();
+        assert_eq!(rooms, ["Main", "configured", "locked", "owned"]);
+    }
+
+    #[test]
+    fn failed_logins_back_off_account_and_address() {
+        let mut server = server(vec![]);
+        let bob = Guessed::Account("bob".to_owned());
+        let eve = Guessed::Account("eve".to_owned());
+        let peer = Guessed::Peer(IpAddr::from([10, 0, 0, 1]));
+        let other = Guessed::Peer(IpAddr::from([10, 0, 0, 2]));
+
+        server.failed(vec![bob.clone(), peer.clone()]);
+        // neither reconnecting nor trying another account helps
+        assert!(server.backing_off(&[bob.clone(), other.clone()]));
+        assert!(server.backing_off(&[eve.clone(), peer]));
+        assert!(!server.backing_off(&[eve, other]));
+
+        server.failed(vec![bob.clone()]);
+        let failures = &server.failures[&bob];
+        assert_eq!(failures.count, 2);
+        assert!(failures.until > Instant::now() + LOGIN_BACKOFF);
+    }
+
+    #[test]
+    fn wrong_room_passwords_back_off_room_and_address() {
+        let mut server = server(vec![]);
+        let room = Guessed::Room("HR".to_owned());
+        let other_room = Guessed::Room("Sales".to_owned());
+        let peer = Guessed::Peer(IpAddr::from([10, 0, 0, 1]));
+        let other = Guessed::Peer(IpAddr::from([10, 0, 0, 2]));
+
+        // sessions without a known address answer for the room only
+        assert_eq!(server.guessed(1, room.clone()), std::slice::from_ref(&room));
+
+        server.failed(vec![room.clone(), peer.clone()]);
+        assert!(server.backing_off(&[room.clone(), other.clone()]));
+        assert!(server.backing_off(&[other_room.clone(), peer]));
+        assert!(!server.backing_off(&[other_room, other]));
+        // the account of the same name is another matter
+        assert!(!server.backing_off(&[Guessed::Account("HR".to_owned())]));
+    }
+
+    #[test]
+    fn names_are_ascii() {
+        assert!(valid_name("bob_1.x-y"));
+        assert!(!valid_name(""));
+        assert!(!valid_name("bob smith"));
+        // cyrillic 'о'
+        assert!(!valid_name("b\u{43e}b"));
+        assert!(!valid_name("\u{e9}mile"));
+    }
+
+    #[test]
+    fn protected_rooms_show_to_members_and_invited_only() {
+        let eve = Identity::Account("eve".to_owned());
+        let guest = Identity::Guest(3);
+        let mut room = Room {
+            password: Some("hash".to_owned()),
+            owner: Some(bob()),
+            ..Room::default()
+        };
+        room.sessions.insert(2);
+        room.invited.insert(eve.clone());
+
+        assert!(room.is_visible_to(3, &guest));
+        assert!(!room.is_readable_by(3, &guest, None));
+        assert!(room.is_readable_by(2, &Identity::Guest(2), None));
+        assert!(room.is_readable_by(1, &bob(), None));
+        assert!(room.is_readable_by(4, &eve, None));
+
+        // a ban outweighs invitation and password alike
+        room.banned.insert(eve.clone());
+        assert!(!room.is_readable_by(4, &eve, None));
+        room.password = None;
+        assert!(room.is_readable_by(3, &guest, None));
+        assert!(!room.is_readable_by(4, &eve, None));
+    }
+
+    #[test]
+    fn guests_are_banned_by_address() {
+        let address = IpAddr::from([10, 0, 0, 1]);
+        let (peer, other) = (Some(address), Some(IpAddr::from([10, 0, 0, 2])));
+        let mut room = Room::default();
+        room.banned_peers.insert(address);
+
+        // reconnecting gets a guest a new session, but not a new address
+        assert!(room.is_banned(&Identity::Guest(5), peer));
+        assert!(!room.is_banned(&Identity::Guest(5), other));
+        assert!(!room.is_banned(&Identity::Guest(5), None));
+        // logged in users answer for their account only
+        assert!(!room.is_banned(&bob(), peer));
+        room.banned.insert(bob());
+        assert!(room.is_banned(&bob(), other));
+    }
+
+    #[test]
+    fn authors_are_matched_by_account() {
+        let mut server = server(vec![]);
+        // guest "bob" left, now someone registered the name
+        server.names.insert(2, "Bob".to_owned());
+        server.users.insert(2);
+        let guest = ChatMessage {
+            sender_account: None,
+            ..message(1, "Main", "secret")
+        };
+        assert!(server.is_author(1, &guest));
+        assert!(!server.is_author(2, &guest));
+
+        let logged_in = message(2, "Main", "hi");
+        assert!(server.is_author(2, &logged_in));
+        assert!(!server.is_author(3, &logged_in));
+    }
+
+    #[test]
+    fn authors_edit_where_they_may_talk_only() {
+        let mut muted = Room {
+            muted: iter::once(bob()).collect(),
+            ..Room::default()
+        };
+        muted.record(message(1, "HR", "hi"));
+        let mut banned = Room {
+            banned: iter::once(bob()).collect(),
+            ..Room::default()
+        };
+        banned.record(message(2, "Sales", "hi"));
+        let mut server = server(vec![("HR", muted), ("Sales", banned)]);
+        let account = Account {
+            name: "bob".to_owned(),
+            hash: "hash".to_owned(),
+        };
+        server.accounts.insert("bob".to_owned(), account);
+
+        let results = System::new("test").block_on(async move {
+            let server = server.start();
+            let id = connect(&server).await;
+            let login = Login {
+                id,
+                user: "bob".to_owned(),
+            };
+            server.send(login).await.unwrap().unwrap();
+            let edit = |message| EditMessage {
+                id,
+                message,
+                body: "bye".to_owned(),
+            };
+
+            let outside = server.send(edit(1)).await.unwrap();
+            let join = Join {
+                id,
+                name: "HR".to_owned(),
+                password_ok: false,
+                hash: None,
+                visibility: Visibility::default(),
+            };
+            server.send(join).await.unwrap().unwrap();
+            let muted = server.send(edit(1)).await.unwrap();
+            let banned = server.send(edit(2)).await.unwrap();
+            [outside, muted, banned]
+        });
+        assert_eq!(
+            results,
+            [
+                Err(RoomError::NotInRoom),
+                Err(RoomError::Muted),
+                // as if it wasn't there, like the rest of the room
+                Err(RoomError::NoSuchMessage),
+            ]
+        );
+    }
+}
@@ -1,5 +1,5 @@
-use std::{io, net};
-use std::io::Error;
+use std::collections::HashSet;
+use std::{io, iter, net};
 use std::str::FromStr;
 use std::time::{Duration, Instant};
 
@@ -9,13 +9,27 @@ use tokio::io::{split, WriteHalf};
 use tokio::net::{TcpListener, TcpStream};
 use tokio_util::codec::FramedRead;
 
-use crate::codec::{ChatCodec, ChatRequest, ChatResponse};
-use crate::server::{self, ChatServer};
+use tcp_chat::codec::{
+    ChatCodec, ChatRequest, ChatResponse, CodecError, Compression, Encoding, ErrorCode, Format,
+    Request, SharedEncoding, Transport, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION,
+};
 
-// chat server sends this message to session
+use crate::accounts::{self, AccountError};
+use crate::rooms;
+use crate::server::{self, ChatServer, Moderation, NameError, RoomError};
+
+/// Optional protocol features this server supports
+const SERVER_CAPABILITIES: &[&str] = &[
+    "format:json",
+    "format:msgpack",
+    "format:cbor",
+    "compress:deflate",
+];
+
+// chat server sends this message to session, a chat message or an event
 #[derive(Message)]
 #[rtype(result = "()")]
-pub struct Message(pub String);
+pub struct Message(pub ChatResponse);
 
 /// ChatSession actor is responsible for tcp peer communication
 pub struct ChatSession {
@@ -25,8 +39,14 @@ pub struct ChatSession {
     addr: Addr<ChatServer>,
     /// Client mus send ping at least onece per 10 seconds, otherwise we drop connection.
     hb: Instant,
-    /// joined room
-    room: String,
+    /// joined rooms
+    rooms: HashSet<String>,
+    /// peer completed `Hello`/`Welcome` handshake
+    handshake: bool,
+    /// payload encoding, shared with the framed reader
+    encoding: SharedEncoding,
+    /// address peer connects from, if known
+    peer: Option<net::IpAddr>,
     /// framed wrapper
     framed: actix::io::FramedWrite<ChatResponse, WriteHalf<TcpStream>, ChatCodec>,
 }
@@ -37,92 +57,426 @@ impl Actor for ChatSession {
     type Context = Context<Self>;
 
     fn started(&mut self, ctx: &mut Self::Context) {
-        // we'll start the heartbeat process on session start
+        // we'll start the heartbeat process on session start, peer joins
+        // the chat server only once it completed the handshake
         self.hb(ctx);
-
-        // register self in chat server AsyncContext::wait register
-        // future within context, but context waits untill this future resolves
-        // before processing any other events.
-        let addr = ctx.address();
-        self.addr
-            .send(server::Connect {
-                addr: addr.recipient(),
-            })
-            .into_actor(self)
-            .then(|res, act, ctx| {
-                match res {
-                    Ok(res) => act.id = res,
-                    // sth is wrong with chat server
-                    _ => ctx.stop(),
-                }
-                actix::fut::ready(())
-            })
-            .wait(ctx);
     }
 
     fn stopping(&mut self, _: &mut Self::Context) -> Running {
         // notify chat server
-        self.addr.do_send(server::Disconnect { id: self.id });
+        if self.handshake {
+            self.addr.do_send(server::Disconnect { id: self.id });
+        }
         Running::Stop
     }
 }
 
-impl actix::io::WriteHandler<io::Error> for ChatSession {}
+impl actix::io::WriteHandler<io::Error> for ChatSession {
+    /// A response too large for a frame is dropped and the peer told so,
+    /// connection is unusable after any other error
+    fn error(&mut self, err: io::Error, ctx: &mut Self::Context) -> Running {
+        match err.get_ref().and_then(|e| e.downcast_ref::<CodecError>()) {
+            Some(e @ CodecError::FrameTooLarge { .. }) => {
+                println!("Response dropped: {}", e);
+                let (code, message) = (e.code(), format!("response dropped: {}", e));
+                // writer is busy reporting this error, tell the peer right after
+                ctx.run_later(Duration::from_secs(0), move |act, _| {
+                    act.error(None, code, message)
+                });
+                Running::Continue
+            }
+            _ => Running::Stop,
+        }
+    }
+}
 
 /// to use framed we have to define io type and codec
-impl StreamHandler<Result<ChatRequest, io::Error>> for ChatSession {
+impl StreamHandler<Result<Request, CodecError>> for ChatSession {
     /// this is main event loop for client requests
-    fn handle(&mut self, msg: Result<ChatRequest, Error>, ctx: &mut Self::Context) {
+    fn handle(&mut self, msg: Result<Request, CodecError>, ctx: &mut Self::Context) {
+        let Request { id, body: msg } = match msg {
+            Ok(msg) => msg,
+            // frame was skipped, stream is still usable so just tell the peer
+            Err(e) if e.is_recoverable() => {
+                println!("Bad frame from peer: {}", e);
+                self.error(None, e.code(), e.to_string());
+                return;
+            }
+            Err(e) => {
+                println!("Connection error: {}", e);
+                ctx.stop();
+                return;
+            }
+        };
+
+        // nothing but a handshake is accepted until the peer introduced itself
+        if !self.handshake {
+            match msg {
+                ChatRequest::Hello {
+                    version,
+                    client_name,
+                    capabilities,
+                } => self.hello(ctx, id, version, client_name, capabilities),
+                _ => self.error(id, ErrorCode::HandshakeRequired, "send Hello first"),
+            }
+            return;
+        }
+
         match msg {
-            Ok(ChatRequest::List) => {
+            ChatRequest::Hello { .. } => {
+                self.error(id, ErrorCode::InvalidRequest, "handshake already completed")
+            }
+            ChatRequest::List => {
                 // send listrooms message to chat server and wait for response
                 println!("List rooms");
                 self.addr
-                    .send(server::ListRooms)
+                    .send(server::ListRooms { id: self.id })
                     .into_actor(self)
-                    .then(|res, act, _| {
+                    .then(move |res, act, _| {
                         match res {
                             Ok(rooms) => {
-                                act.framed.write(ChatResponse::Rooms(rooms));
+                                act.framed.write(ChatResponse::Rooms {
+                                    rooms,
+                                    request_id: id,
+                                });
                             }
-                            _ => println!("Something went wrong"),
+                            _ => act.error(id, ErrorCode::Internal, "could not list rooms"),
                         }
                         actix::fut::ready(())
                     })
                     .wait(ctx)
                 // .wait(ctx) pauses all events in context, so actor wont receive any new messages until it get list of rooms back
             }
-            Ok(ChatRequest::Join(name)) => {
+            ChatRequest::Join {
+                room: name,
+                password,
+                visibility,
+            } => {
                 println!("Join to room: {}", name);
-                self.room = name.clone();
-                self.addr.do_send(server::Join {
-                    id: self.id,
-                    name: name.clone(),
-                });
-                self.framed.write(ChatResponse::Joined(name));
+                rooms::join(
+                    self.addr.clone(),
+                    self.id,
+                    name.clone(),
+                    password,
+                    visibility,
+                )
+                .into_actor(self)
+                .then(move |res, act, _| {
+                    match res {
+                        Ok(()) => {
+                            act.rooms.insert(name.clone());
+                            act.framed.write(ChatResponse::Joined {
+                                room: name,
+                                request_id: id,
+                            });
+                        }
+                        Err(e) => act.refused(id, e, format!("{}: {}", e, name)),
+                    }
+                    actix::fut::ready(())
+                })
+                .wait(ctx);
             }
-            Ok(ChatRequest::Message(message)) => {
+            ChatRequest::Leave(name) => {
+                if !self.rooms.contains(&name) {
+                    self.error(id, ErrorCode::NotInRoom, format!("not in room: {}", name));
+                    return;
+                }
+                println!("Leave room: {}", name);
+                self.addr
+                    .send(server::Leave {
+                        id: self.id,
+                        name: name.clone(),
+                    })
+                    .into_actor(self)
+                    .then(move |res, act, _| {
+                        match res {
+                            Ok(Ok(())) => {
+                                act.rooms.remove(&name);
+                                act.framed.write(ChatResponse::Left {
+                                    room: name,
+                                    request_id: id,
+                                });
+                            }
+                            Ok(Err(e)) => act.refused(id, e, format!("{}: {}", e, name)),
+                            _ => act.error(id, ErrorCode::Internal, "could not leave room"),
+                        }
+                        actix::fut::ready(())
+                    })
+                    .wait(ctx);
+            }
+            ChatRequest::Message { room, body } => {
+                if !self.rooms.contains(&room) {
+                    self.error(id, ErrorCode::NotInRoom, format!("not in room: {}", room));
+                    return;
+                }
                 // send message to chat server
-                println!("Peer message: {}", message);
-                self.addr.do_send(server::Message {
-                    id: self.id,
-                    msg: message,
-                    room: self.room.clone(),
-                })
+                println!("Peer message: {}", body);
+                self.addr
+                    .send(server::Message {
+                        id: self.id,
+                        msg: body,
+                        room: room.clone(),
+                    })
+                    .into_actor(self)
+                    .then(move |res, act, _| {
+                        match res {
+                            // tell message id only when client wants to correlate
+                            Ok(Ok(message)) => {
+                                if id.is_some() {
+                                    act.framed.write(ChatResponse::Sent {
+                                        id: message,
+                                        request_id: id,
+                                    });
+                                }
+                            }
+                            Ok(Err(e)) => act.refused(id, e, format!("{}: {}", e, room)),
+                            _ => act.error(id, ErrorCode::Internal, "could not send message"),
+                        }
+                        actix::fut::ready(())
+                    })
+                    .wait(ctx);
+            }
+            ChatRequest::EditMessage { id: message, body } => {
+                self.addr
+                    .send(server::EditMessage {
+                        id: self.id,
+                        message,
+                        body,
+                    })
+                    .into_actor(self)
+                    .then(move |res, act, _| {
+                        match res {
+                            Ok(Err(e)) => act.refused(id, e, format!("{}: {}", e, message)),
+                            res => act.room_changed(id, res.unwrap_or(Err(RoomError::Internal))),
+                        }
+                        actix::fut::ready(())
+                    })
+                    .wait(ctx);
+            }
+            ChatRequest::DeleteMessage { id: message } => {
+                self.addr
+                    .send(server::DeleteMessage {
+                        id: self.id,
+                        message,
+                    })
+                    .into_actor(self)
+                    .then(move |res, act, _| {
+                        act.room_changed(id, res.unwrap_or(Err(RoomError::Internal)));
+                        actix::fut::ready(())
+                    })
+                    .wait(ctx);
+            }
+            ChatRequest::DirectMessage { to, body } => {
+                self.addr
+                    .send(server::Direct {
+                        id: self.id,
+                        to: to.clone(),
+                        msg: body,
+                    })
+                    .into_actor(self)
+                    .then(move |res, act, _| {
+                        match res {
+                            Ok(Ok(())) => act.framed.write(ChatResponse::Ack { request_id: id }),
+                            Ok(Err(e)) => act.refused(id, e, format!("{}: {}", e, to)),
+                            _ => act.error(id, ErrorCode::Internal, "could not send message"),
+                        }
+                        actix::fut::ready(())
+                    })
+                    .wait(ctx);
+            }
+            ChatRequest::SetName(name) => {
+                self.addr
+                    .send(server::SetName { id: self.id, name })
+                    .into_actor(self)
+                    .then(move |res, act, _| {
+                        match res {
+                            // server sends `Renamed` itself, ack only if client correlates
+                            Ok(Ok(())) => {
+                                if id.is_some() {
+                                    act.framed.write(ChatResponse::Ack { request_id: id });
+                                }
+                            }
+                            Ok(Err(NameError::Throttled(e))) => act.refused(id, e, e.to_string()),
+                            Ok(Err(e)) => act.error(id, e.code(), e.to_string()),
+                            _ => act.error(id, ErrorCode::Internal, "could not set name"),
+                        }
+                        actix::fut::ready(())
+                    })
+                    .wait(ctx);
+            }
+            ChatRequest::Register { user, password } => {
+                accounts::register(self.addr.clone(), self.id, user, password)
+                    .into_actor(self)
+                    .then(move |res, act, _| {
+                        act.logged_in(id, res);
+                        actix::fut::ready(())
+                    })
+                    .wait(ctx);
+            }
+            ChatRequest::Login { user, password } => {
+                accounts::login(self.addr.clone(), self.id, user, password)
+                    .into_actor(self)
+                    .then(move |res, act, _| {
+                        act.logged_in(id, res);
+                        actix::fut::ready(())
+                    })
+                    .wait(ctx);
+            }
+            ChatRequest::Kick { room, user } => {
+                self.moderate(ctx, id, room, user, Moderation::Kick)
+            }
+            ChatRequest::Ban { room, user } => self.moderate(ctx, id, room, user, Moderation::Ban),
+            ChatRequest::Unban { room, user } => {
+                self.moderate(ctx, id, room, user, Moderation::Unban)
+            }
+            ChatRequest::Mute { room, user } => {
+                self.moderate(ctx, id, room, user, Moderation::Mute)
+            }
+            ChatRequest::Unmute { room, user } => {
+                self.moderate(ctx, id, room, user, Moderation::Unmute)
+            }
+            ChatRequest::Invite { room, user } => {
+                self.moderate(ctx, id, room, user, Moderation::Invite)
+            }
+            ChatRequest::SetVisibility { room, visibility } => {
+                self.addr
+                    .send(server::SetVisibility {
+                        id: self.id,
+                        room,
+                        visibility,
+                    })
+                    .into_actor(self)
+                    .then(move |res, act, _| {
+                        act.room_changed(id, res.unwrap_or(Err(RoomError::Internal)));
+                        actix::fut::ready(())
+                    })
+                    .wait(ctx);
+            }
+            ChatRequest::SetPassword { room, password } => {
+                rooms::set_password(self.addr.clone(), self.id, room, password)
+                    .into_actor(self)
+                    .then(move |res, act, _| {
+                        act.room_changed(id, res);
+                        actix::fut::ready(())
+                    })
+                    .wait(ctx);
+            }
+            ChatRequest::SetTopic { room, topic } => {
+                self.addr
+                    .send(server::SetTopic {
+                        id: self.id,
+                        room,
+                        topic,
+                    })
+                    .into_actor(self)
+                    .then(move |res, act, _| {
+                        act.room_changed(id, res.unwrap_or(Err(RoomError::Internal)));
+                        actix::fut::ready(())
+                    })
+                    .wait(ctx);
+            }
+            ChatRequest::SetDescription { room, description } => {
+                self.addr
+                    .send(server::SetDescription {
+                        id: self.id,
+                        room,
+                        description,
+                    })
+                    .into_actor(self)
+                    .then(move |res, act, _| {
+                        act.room_changed(id, res.unwrap_or(Err(RoomError::Internal)));
+                        actix::fut::ready(())
+                    })
+                    .wait(ctx);
+            }
+            ChatRequest::AddModerator { room, user } => {
+                self.moderate(ctx, id, room, user, Moderation::AddModerator)
+            }
+            ChatRequest::RemoveModerator { room, user } => {
+                self.moderate(ctx, id, room, user, Moderation::RemoveModerator)
+            }
+            ChatRequest::Who(room) => {
+                self.addr
+                    .send(server::Who {
+                        id: self.id,
+                        room: room.clone(),
+                    })
+                    .into_actor(self)
+                    .then(move |res, act, _| {
+                        match res {
+                            Ok(Some(members)) => {
+                                act.framed.write(ChatResponse::Members {
+                                    room,
+                                    members,
+                                    request_id: id,
+                                });
+                            }
+                            Ok(None) => act.error(
+                                id,
+                                ErrorCode::NoSuchRoom,
+                                format!("no such room: {}", room),
+                            ),
+                            _ => act.error(id, ErrorCode::Internal, "could not list members"),
+                        }
+                        actix::fut::ready(())
+                    })
+                    .wait(ctx);
             }
             // we update heartbeat time on ping from peer
-            Ok(ChatRequest::Ping) => self.hb = Instant::now(),
-            _ => ctx.stop(),
+            ChatRequest::Ping => self.hb = Instant::now(),
+            ChatRequest::History {
+                room,
+                before,
+                limit,
+            } => {
+                self.addr
+                    .send(server::History {
+                        id: self.id,
+                        room: room.clone(),
+                        before,
+                        limit,
+                    })
+                    .into_actor(self)
+                    .then(move |res, act, _| {
+                        match res {
+                            Ok(Some(messages)) => {
+                                act.framed.write(ChatResponse::History {
+                                    room,
+                                    messages,
+                                    request_id: id,
+                                });
+                            }
+                            Ok(None) => act.error(
+                                id,
+                                ErrorCode::NoSuchRoom,
+                                format!("no such room: {}", room),
+                            ),
+                            _ => act.error(id, ErrorCode::Internal, "could not fetch history"),
+                        }
+                        actix::fut::ready(())
+                    })
+                    .wait(ctx);
+            }
         }
     }
 }
 
-/// Handler for Message, chat server sends this message, we just send string to peer
+/// Handler for Message, chat server sends this message, we just send it to peer
 impl Handler<Message> for ChatSession {
     type Result = ();
     fn handle(&mut self, msg: Message, _: &mut Self::Context) -> Self::Result {
+        // nothing goes out before `Welcome`
+        if !self.handshake {
+            return;
+        }
+        // server already took us out of the room
+        if let ChatResponse::Kicked { room, session, .. } = &msg.0 {
+            if *session == self.id {
+                self.rooms.remove(room);
+            }
+        }
         // send message to peer
-        self.framed.write(ChatResponse::Message(msg.0));
+        self.framed.write(msg.0);
     }
 }
 
@@ -131,15 +485,173 @@ impl ChatSession {
     pub fn new(
         addr: Addr<ChatServer>,
         framed: actix::io::FramedWrite<ChatResponse, WriteHalf<TcpStream>, ChatCodec>,
+        encoding: SharedEncoding,
+        peer: Option<net::IpAddr>,
     ) -> ChatSession {
         ChatSession {
             id: 0,
             addr,
             hb: Instant::now(),
-            room: "Main".to_owned(),
+            rooms: iter::once("Main".to_owned()).collect(),
+            handshake: false,
+            encoding,
+            peer,
             framed,
         }
     }
+
+    /// Report a failed request to the peer, connection stays open
+    fn error(&mut self, request_id: Option<u64>, code: ErrorCode, message: impl Into<String>) {
+        self.framed.write(ChatResponse::Error {
+            code,
+            message: message.into(),
+            request_id,
+        });
+    }
+
+    /// Report outcome of `Register` or `Login` to the peer
+    fn logged_in(&mut self, request_id: Option<u64>, res: Result<String, AccountError>) {
+        match res {
+            Ok(user) => self
+                .framed
+                .write(ChatResponse::LoggedIn { user, request_id }),
+            Err(AccountError::Throttled(e)) => self.refused(request_id, e, e.to_string()),
+            Err(e) => self.error(request_id, e.code(), e.to_string()),
+        }
+    }
+
+    /// Ask chat server to moderate `user` of `room`
+    fn moderate(
+        &mut self,
+        ctx: &mut Context<Self>,
+        request_id: Option<u64>,
+        room: String,
+        user: String,
+        action: Moderation,
+    ) {
+        self.addr
+            .send(server::Moderate {
+                id: self.id,
+                room,
+                user,
+                action,
+            })
+            .into_actor(self)
+            .then(move |res, act, _| {
+                act.room_changed(request_id, res.unwrap_or(Err(RoomError::Internal)));
+                actix::fut::ready(())
+            })
+            .wait(ctx);
+    }
+
+    /// Report refused message or request to the peer, closing the connection
+    /// if it keeps flooding
+    fn refused(&mut self, request_id: Option<u64>, e: RoomError, message: String) {
+        self.error(request_id, e.code(), message);
+        if e == RoomError::Flooding {
+            // session stops once the error frame is flushed
+            self.framed.close();
+        }
+    }
+
+    /// Report outcome of moderating or configuring a room, or of changing a
+    /// message, to the peer, acknowledged only when client wants to correlate
+    fn room_changed(&mut self, request_id: Option<u64>, res: Result<(), RoomError>) {
+        match res {
+            Ok(()) => {
+                if request_id.is_some() {
+                    self.framed.write(ChatResponse::Ack { request_id });
+                }
+            }
+            Err(e) => self.error(request_id, e.code(), e.to_string()),
+        }
+    }
+
+    /// Negotiate protocol version and payload encoding with the peer, register
+    /// it in chat server and answer with `Welcome`. First format and
+    /// compression capability of the client we support wins.
+    ///
+    /// Peers older than `MIN_PROTOCOL_VERSION` are told so and disconnected.
+    fn hello(
+        &mut self,
+        ctx: &mut Context<Self>,
+        request_id: Option<u64>,
+        version: u16,
+        client_name: String,
+        capabilities: Vec<String>,
+    ) {
+        if version < MIN_PROTOCOL_VERSION {
+            println!(
+                "Client {} speaks unsupported protocol version {}, disconnecting!",
+                client_name, version
+            );
+            self.error(
+                request_id,
+                ErrorCode::UnsupportedVersion,
+                format!(
+                    "protocol version {} is not supported, minimum is {}",
+                    version, MIN_PROTOCOL_VERSION
+                ),
+            );
+            // session stops once the error frame is flushed
+            self.framed.close();
+            return;
+        }
+
+        println!(
+            "Hello from {} (protocol {}, capabilities {:?})",
+            client_name, version, capabilities
+        );
+        let supported = capabilities
+            .iter()
+            .filter(|c| SERVER_CAPABILITIES.contains(&c.as_str()));
+        let encoding = Encoding {
+            format: supported
+                .clone()
+                .find_map(|c| Format::from_capability(c))
+                .unwrap_or_default(),
+            compression: supported
+                .clone()
+                .find_map(|c| Compression::from_capability(c))
+                .unwrap_or_default(),
+        };
+
+        // register self in chat server AsyncContext::wait register
+        // future within context, but context waits untill this future resolves
+        // before processing any other events, so `Welcome` goes out first.
+        let addr = ctx.address();
+        self.addr
+            .send(server::Connect {
+                addr: addr.recipient(),
+                transport: Transport::Tcp,
+                peer: self.peer,
+            })
+            .into_actor(self)
+            .then(move |res, act, ctx| {
+                match res {
+                    Ok(res) => {
+                        act.id = res;
+                        act.handshake = true;
+                        act.framed.write(ChatResponse::Welcome {
+                            version: version.min(PROTOCOL_VERSION),
+                            session_id: act.id,
+                            server_capabilities: SERVER_CAPABILITIES
+                                .iter()
+                                .map(|c| (*c).to_owned())
+                                .collect(),
+                            format: encoding.format,
+                            compression: encoding.compression,
+                        });
+                        // welcome itself is already encoded, switch for everything that follows
+                        act.encoding.set(encoding);
+                    }
+                    // sth is wrong with chat server
+                    _ => ctx.stop(),
+                }
+                actix::fut::ready(())
+            })
+            .wait(ctx);
+    }
     /// helper method that sends ping to client every second.
     /// also this method check heartbeats from client
     fn hb(&self, ctx: &mut Context<Self>) {
@@ -149,20 +661,19 @@ impl ChatSession {
                 // heatbeat timed out
                 println!("Client heatbeat failed, disconnecting!");
 
-                // notify chat server
-                act.addr.do_send(server::Disconnect { id: act.id });
-
-                // stop actor
+                // stop actor, chat server is notified when it stops
                 ctx.stop();
             }
-            act.framed.write(ChatResponse::Ping);
+            if act.handshake {
+                act.framed.write(ChatResponse::Ping);
+            }
             // if we can not send message to sink, sink is closed (disconnected)
         });
     }
 }
 
 /// Define tcp server that will accept incoming tcp connection and create chat actors.
-pub fn tcp_server(_s: &str, server: Addr<ChatServer>) {
+pub fn tcp_server(_s: &str, server: Addr<ChatServer>, max_frame_size: usize) {
     // Create serve listener
     let addr = net::SocketAddr::from_str("127.0.0.1:12345").unwrap();
 
@@ -176,12 +687,18 @@ pub fn tcp_server(_s: &str, server: Addr<ChatServer>) {
             match stream {
                 Ok(stream) => {
                     let server = server.clone();
+                    let peer = stream.peer_addr().ok().map(|addr| addr.ip());
                     ChatSession::create(|ctx| {
                         let (r, w) = split(stream);
-                        ChatSession::add_stream(FramedRead::new(r, ChatCodec), ctx);
+                        let encoding = SharedEncoding::default();
+                        let codec = ChatCodec::new(max_frame_size, encoding.clone());
+                        ChatSession::add_stream(FramedRead::new(r, codec), ctx);
+                        let codec = ChatCodec::new(max_frame_size, encoding.clone());
                         ChatSession::new(
                             server,
-                            actix::io::FramedWrite::new(w, ChatCodec, ctx),
+                            actix::io::FramedWrite::new(w, codec, ctx),
+                            encoding,
+                            peer,
                         )
                     });
                 }
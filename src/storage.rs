@@ -0,0 +1,194 @@
+//! Append-only log of rooms, messages, accounts and room settings, so chat
+//! survives server restarts.
+//!
+//! Every change of durable state is appended to the log as one JSON line.
+//! On startup `ChatServer` replays the log, then rewrites it with just the
+//! state it kept, so the file doesn't grow forever.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+use serde_json as json;
+
+use tcp_chat::codec::{ChatMessage, Visibility};
+
+/// One entry of the log
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "kind", content = "data")]
+pub enum Record {
+    /// Room was created
+    Room(String),
+    /// Room was removed, its messages with it
+    RoomRemoved(String),
+    /// Room was created at `timestamp`, milliseconds since unix epoch
+    Created { room: String, timestamp: u64 },
+    /// Room topic changed, `None` if it was cleared
+    Topic { room: String, topic: Option<String> },
+    /// Room description changed, `None` if it was cleared
+    Description {
+        room: String,
+        description: Option<String>,
+    },
+    /// Message was posted to a room
+    Message(ChatMessage),
+    /// Message ids up to this one were handed out, whether or not their
+    /// messages are still in the log
+    LastMessageId(u64),
+    /// User registered, `hash` is the salted password hash
+    Account { name: String, hash: String },
+    /// Account `user` owns the room, it created it
+    Owner { room: String, user: String },
+    /// Account `user` was made moderator of the room
+    Moderator { room: String, user: String },
+    /// Account `user` is no longer moderator of the room
+    ModeratorRemoved { room: String, user: String },
+    /// Account `user` was banned from the room
+    Ban { room: String, user: String },
+    /// Ban of account `user` was lifted
+    Unban { room: String, user: String },
+    /// Account `user` was invited to the room
+    Invite { room: String, user: String },
+    /// Room visibility changed
+    Visibility {
+        room: String,
+        visibility: Visibility,
+    },
+    /// Room join password changed, `hash` is the salted password hash, `None`
+    /// if the room is open
+    Password { room: String, hash: Option<String> },
+}
+
+/// Log file records are appended to
+pub struct Storage {
+    path: PathBuf,
+    file: File,
+}
+
+impl Storage {
+    /// Open log at `path`, creating it if missing, and read back its records.
+    ///
+    /// Lines that can't be parsed, e.g. the last one after a crash in the
+    /// middle of a write, are skipped.
+    pub fn open(path: impl AsRef<Path>) -> io::Result<(Storage, Vec<Record>)> {
+        let path = path.as_ref().to_owned();
+        let mut records = Vec::new();
+
+        match fs::read(&path) {
+            Ok(log) => {
+                // split bytes, not text, a torn line may end mid character
+                for (n, line) in log.split(|b| *b == b'\n').enumerate() {
+                    if line.is_empty() {
+                        continue;
+                    }
+                    match json::from_slice(line) {
+                        Ok(record) => records.push(record),
+                        Err(e) => println!("Skipping bad record {} of {:?}: {}", n + 1, path, e),
+                    }
+                }
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => (),
+            Err(e) => return Err(e),
+        }
+
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        Ok((Storage { path, file }, records))
+    }
+
+    /// Append record to the log
+    pub fn append(&mut self, record: &Record) -> io::Result<()> {
+        let mut line = json::to_vec(record)?;
+        line.push(b'\n');
+        // single write, so a crash can only ever cut the last line short
+        self.file.write_all(&line)
+    }
+
+    /// Replace the whole log with `records`.
+    ///
+    /// New log is written next to the old one and renamed over it, the old
+    /// log stays intact if anything goes wrong.
+    pub fn compact<'a>(&mut self, records: impl IntoIterator<Item = &'a Record>) -> io::Result<()> {
+        let tmp = self.path.with_extension("tmp");
+        {
+            let mut file = io::BufWriter::new(File::create(&tmp)?);
+            for record in records {
+                json::to_writer(&mut file, record)?;
+                file.write_all(b"\n")?;
+            }
+            file.into_inner()?.sync_all()?;
+        }
+        fs::rename(&tmp, &self.path)?;
+
+        self.file = OpenOptions::new().append(true).open(&self.path)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn room(name: &str) -> Record {
+        Record::Room(name.to_owned())
+    }
+
+    fn names(records: &[Record]) -> Vec<&str> {
+        records
+            .iter()
+            .map(|r| match r {
+                Record::Room(name) => name.as_str(),
+                r => panic!("unexpected record {:?}", r),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn appended_records_are_read_back() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("chat.log");
+
+        let (mut storage, records) = Storage::open(&path).unwrap();
+        assert!(records.is_empty());
+        storage.append(&room("a")).unwrap();
+        storage.append(&room("b")).unwrap();
+        drop(storage);
+
+        let (_, records) = Storage::open(&path).unwrap();
+        assert_eq!(names(&records), ["a", "b"]);
+    }
+
+    #[test]
+    fn compact_replaces_log() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("chat.log");
+
+        let (mut storage, _) = Storage::open(&path).unwrap();
+        storage.append(&room("a")).unwrap();
+        storage.append(&room("b")).unwrap();
+        storage.compact(&[room("c")]).unwrap();
+        // appends go to the new log
+        storage.append(&room("d")).unwrap();
+        drop(storage);
+
+        let (_, records) = Storage::open(&path).unwrap();
+        assert_eq!(names(&records), ["c", "d"]);
+        assert!(!path.with_extension("tmp").exists());
+    }
+
+    #[test]
+    fn bad_lines_are_skipped() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("chat.log");
+
+        let mut log = b"{\"kind\":\"Room\",\"data\":\"a\"}\nnot json\n\n".to_vec();
+        log.extend_from_slice(b"{\"kind\":\"Room\",\"data\":\"b\"}\n");
+        // crash cut the last line short in the middle of a character
+        let torn = "{\"kind\":\"Room\",\"data\":\"\u{e9}\"}";
+        log.extend_from_slice(&torn.as_bytes()[..torn.len() - 3]);
+        fs::write(&path, log).unwrap();
+
+        let (_, records) = Storage::open(&path).unwrap();
+        assert_eq!(names(&records), ["a", "b"]);
+    }
+}
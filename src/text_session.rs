@@ -0,0 +1,740 @@
+//! Line oriented tcp sessions for people poking the chat with `nc` or `telnet`.
+//!
+//! Every line from the peer is either a slash command understood by the
+//! websocket session too (see `commands`) or a message for the current room.
+//! Everything sent back is plain text, one line per message.
+//!
+//! Plain text peers don't ping, a session is dropped once the peer has been
+//! silent for `IDLE_TIMEOUT`, so half-open connections don't keep their name
+//! or account forever.
+
+use std::collections::HashSet;
+use std::future::Future;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+use std::{iter, net};
+
+use actix::prelude::*;
+use futures::StreamExt;
+use tokio::io::{split, WriteHalf};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_util::codec::{FramedRead, LinesCodec, LinesCodecError};
+
+use tcp_chat::codec::{ChatResponse, Escaped, Transport, Visibility};
+
+use crate::accounts::{self, AccountError};
+use crate::commands::{self, Command};
+use crate::rooms;
+use crate::server::{self, ChatServer, NameError, RoomError};
+use crate::session;
+
+/// Longest line accepted from peer, rest of a longer line is discarded
+const MAX_LINE_LENGTH: usize = 64 * 1024;
+/// How long a peer may stay silent before it is disconnected
+const IDLE_TIMEOUT: Duration = Duration::from_secs(15 * 60);
+/// How often idle peers are looked for
+const IDLE_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+/// TextSession actor is responsible for line oriented tcp peer communication
+pub struct TextSession {
+    /// unique session id
+    id: usize,
+    /// this is address of chat server
+    addr: Addr<ChatServer>,
+    /// last time peer sent a line, dropped after `IDLE_TIMEOUT` without one
+    hb: Instant,
+    /// joined rooms
+    rooms: HashSet<String>,
+    /// room plain messages go to, one of `rooms`
+    room: Option<String>,
+    /// id of the latest message sent, what `/edit` and `/delete` act on
+    last_message: Option<u64>,
+    /// address peer connects from, if known
+    peer: Option<net::IpAddr>,
+    /// framed wrapper
+    framed: actix::io::FramedWrite<String, WriteHalf<TcpStream>, LinesCodec>,
+}
+
+impl Actor for TextSession {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        // no heartbeats here, plain text peers don't ping, session ends when
+        // peer closes the connection or stays silent for too long
+        self.idle(ctx);
+
+        let addr = ctx.address();
+        self.addr
+            .send(server::Connect {
+                addr: addr.recipient(),
+                transport: Transport::Text,
+                peer: self.peer,
+            })
+            .into_actor(self)
+            .then(|res, act, ctx| {
+                match res {
+                    Ok(res) => act.id = res,
+                    // sth is wrong with chat server
+                    _ => ctx.stop(),
+                }
+                actix::fut::ready(())
+            })
+            .wait(ctx);
+    }
+
+    fn stopping(&mut self, _: &mut Self::Context) -> Running {
+        // notify chat server
+        self.addr.do_send(server::Disconnect { id: self.id });
+        Running::Stop
+    }
+}
+
+impl actix::io::WriteHandler<LinesCodecError> for TextSession {}
+
+/// Every line from peer is a command or a message
+impl StreamHandler<Result<String, LinesCodecError>> for TextSession {
+    fn handle(&mut self, msg: Result<String, LinesCodecError>, ctx: &mut Self::Context) {
+        let line = match msg {
+            Ok(line) => line,
+            Err(LinesCodecError::MaxLineLengthExceeded) => {
+                self.write("!!! line is too long");
+                return;
+            }
+            Err(LinesCodecError::Io(_)) => {
+                ctx.stop();
+                return;
+            }
+        };
+
+        self.hb = Instant::now();
+        let m = line.trim();
+        if m.is_empty() {
+            return;
+        }
+        let command = match commands::parse(m) {
+            Ok(command) => command,
+            Err(e) => {
+                self.write(format!("!!! {}", e));
+                return;
+            }
+        };
+        match command {
+            Command::List => {
+                self.addr
+                    .send(server::ListRooms { id: self.id })
+                    .into_actor(self)
+                    .then(|res, act, _| {
+                        match res {
+                            Ok(rooms) => {
+                                for room in rooms {
+                                    act.write(room.to_string());
+                                }
+                            }
+                            _ => act.write("!!! could not list rooms"),
+                        }
+                        actix::fut::ready(())
+                    })
+                    .wait(ctx);
+            }
+            Command::Join { room, password } => self.join(ctx, room, password, None),
+            Command::Create {
+                room,
+                visibility,
+                password,
+            } => self.join(ctx, room, password, Some(visibility)),
+            Command::Leave(name) => {
+                // current room unless told otherwise
+                let name = match name.or_else(|| self.room.clone()) {
+                    Some(name) => name,
+                    None => {
+                        self.write("!!! room name is required");
+                        return;
+                    }
+                };
+                if !self.rooms.contains(&name) {
+                    self.write(format!("!!! not in room: {}", name));
+                    return;
+                }
+                self.addr
+                    .send(server::Leave {
+                        id: self.id,
+                        name: name.clone(),
+                    })
+                    .into_actor(self)
+                    .then(move |res, act, _| {
+                        match res {
+                            Ok(Ok(())) => {
+                                act.rooms.remove(&name);
+                                if act.room.as_ref() == Some(&name) {
+                                    act.room = act.rooms.iter().next().cloned();
+                                }
+                                act.write("left");
+                            }
+                            Ok(Err(e)) => act.refused(e),
+                            _ => act.write("!!! could not leave room"),
+                        }
+                        actix::fut::ready(())
+                    })
+                    .wait(ctx);
+            }
+            Command::Room(name) => {
+                if self.rooms.contains(&name) {
+                    self.room = Some(name);
+                } else {
+                    self.write(format!("!!! not in room: {}", name));
+                }
+            }
+            Command::Name(name) => {
+                // server announces the new name to us and our rooms
+                self.addr
+                    .send(server::SetName { id: self.id, name })
+                    .into_actor(self)
+                    .then(|res, act, _| {
+                        match res {
+                            Ok(Ok(())) => (),
+                            Ok(Err(NameError::Throttled(e))) => act.refused(e),
+                            Ok(Err(e)) => act.write(format!("!!! {}", e)),
+                            _ => act.write("!!! could not set name"),
+                        }
+                        actix::fut::ready(())
+                    })
+                    .wait(ctx);
+            }
+            Command::Direct { to, body } => {
+                self.addr
+                    .send(server::Direct {
+                        id: self.id,
+                        to: to.clone(),
+                        msg: body,
+                    })
+                    .into_actor(self)
+                    .then(move |res, act, _| {
+                        match res {
+                            Ok(Ok(())) => (),
+                            Ok(Err(RoomError::NoSuchUser)) => {
+                                act.write(format!("!!! no such user: {}", to))
+                            }
+                            Ok(Err(e)) => act.refused(e),
+                            _ => act.write("!!! could not send message"),
+                        }
+                        actix::fut::ready(())
+                    })
+                    .wait(ctx);
+            }
+            Command::Register { user, password } => {
+                let res = accounts::register(self.addr.clone(), self.id, user, password);
+                self.logged_in(ctx, res);
+            }
+            Command::Login { user, password } => {
+                let res = accounts::login(self.addr.clone(), self.id, user, password);
+                self.logged_in(ctx, res);
+            }
+            Command::Moderate { action, user } => {
+                let room = match &self.room {
+                    Some(room) => room.clone(),
+                    None => {
+                        self.write("!!! join a room first");
+                        return;
+                    }
+                };
+                self.addr
+                    .send(server::Moderate {
+                        id: self.id,
+                        room,
+                        user,
+                        action,
+                    })
+                    .into_actor(self)
+                    .then(|res, act, _| {
+                        match res {
+                            Ok(Ok(())) => act.write("done"),
+                            Ok(Err(e)) => act.write(format!("!!! {}", e)),
+                            _ => act.write("!!! could not moderate room"),
+                        }
+                        actix::fut::ready(())
+                    })
+                    .wait(ctx);
+            }
+            Command::Visibility(visibility) => {
+                let room = match &self.room {
+                    Some(room) => room.clone(),
+                    None => {
+                        self.write("!!! join a room first");
+                        return;
+                    }
+                };
+                self.addr
+                    .send(server::SetVisibility {
+                        id: self.id,
+                        room,
+                        visibility,
+                    })
+                    .into_actor(self)
+                    .then(|res, act, _| {
+                        match res {
+                            Ok(Ok(())) => act.write("done"),
+                            Ok(Err(e)) => act.write(format!("!!! {}", e)),
+                            _ => act.write("!!! could not change room"),
+                        }
+                        actix::fut::ready(())
+                    })
+                    .wait(ctx);
+            }
+            Command::Password(password) => {
+                let room = match &self.room {
+                    Some(room) => room.clone(),
+                    None => {
+                        self.write("!!! join a room first");
+                        return;
+                    }
+                };
+                rooms::set_password(self.addr.clone(), self.id, room, password)
+                    .into_actor(self)
+                    .then(|res, act, _| {
+                        match res {
+                            Ok(()) => act.write("done"),
+                            Err(e) => act.write(format!("!!! {}", e)),
+                        }
+                        actix::fut::ready(())
+                    })
+                    .wait(ctx);
+            }
+            Command::Topic(topic) => {
+                let room = match &self.room {
+                    Some(room) => room.clone(),
+                    None => {
+                        self.write("!!! join a room first");
+                        return;
+                    }
+                };
+                self.addr
+                    .send(server::SetTopic {
+                        id: self.id,
+                        room,
+                        topic,
+                    })
+                    .into_actor(self)
+                    .then(|res, act, _| {
+                        match res {
+                            Ok(Ok(())) => (),
+                            Ok(Err(e)) => act.write(format!("!!! {}", e)),
+                            _ => act.write("!!! could not change room"),
+                        }
+                        actix::fut::ready(())
+                    })
+                    .wait(ctx);
+            }
+            Command::Describe(description) => {
+                let room = match &self.room {
+                    Some(room) => room.clone(),
+                    None => {
+                        self.write("!!! join a room first");
+                        return;
+                    }
+                };
+                self.addr
+                    .send(server::SetDescription {
+                        id: self.id,
+                        room,
+                        description,
+                    })
+                    .into_actor(self)
+                    .then(|res, act, _| {
+                        match res {
+                            Ok(Ok(())) => act.write("done"),
+                            Ok(Err(e)) => act.write(format!("!!! {}", e)),
+                            _ => act.write("!!! could not change room"),
+                        }
+                        actix::fut::ready(())
+                    })
+                    .wait(ctx);
+            }
+            Command::Edit(body) => {
+                let message = match self.last_message {
+                    Some(message) => message,
+                    None => {
+                        self.write("!!! no message to edit");
+                        return;
+                    }
+                };
+                self.addr
+                    .send(server::EditMessage {
+                        id: self.id,
+                        message,
+                        body,
+                    })
+                    .into_actor(self)
+                    .then(|res, act, _| {
+                        match res {
+                            // edited message comes back like any other
+                            Ok(Ok(())) => (),
+                            Ok(Err(e)) => act.refused(e),
+                            _ => act.write("!!! could not edit message"),
+                        }
+                        actix::fut::ready(())
+                    })
+                    .wait(ctx);
+            }
+            Command::Delete(message) => {
+                // own latest message unless told otherwise
+                let message = match message.or(self.last_message) {
+                    Some(message) => message,
+                    None => {
+                        self.write("!!! message id is required");
+                        return;
+                    }
+                };
+                self.addr
+                    .send(server::DeleteMessage {
+                        id: self.id,
+                        message,
+                    })
+                    .into_actor(self)
+                    .then(|res, act, _| {
+                        match res {
+                            Ok(Ok(())) => (),
+                            Ok(Err(e)) => act.write(format!("!!! {}", e)),
+                            _ => act.write("!!! could not delete message"),
+                        }
+                        actix::fut::ready(())
+                    })
+                    .wait(ctx);
+            }
+            Command::Who(room) => {
+                // current room unless told otherwise
+                let room = match room.or_else(|| self.room.clone()) {
+                    Some(room) => room,
+                    None => {
+                        self.write("!!! room name is required");
+                        return;
+                    }
+                };
+                self.addr
+                    .send(server::Who {
+                        id: self.id,
+                        room: room.clone(),
+                    })
+                    .into_actor(self)
+                    .then(move |res, act, _| {
+                        match res {
+                            Ok(Some(members)) => {
+                                for member in members {
+                                    act.write(member.to_string());
+                                }
+                            }
+                            Ok(None) => act.write(format!("!!! no such room: {}", room)),
+                            _ => act.write("!!! could not list members"),
+                        }
+                        actix::fut::ready(())
+                    })
+                    .wait(ctx);
+            }
+            Command::History(before) => {
+                let room = match &self.room {
+                    Some(room) => room.clone(),
+                    None => {
+                        self.write("!!! join a room first");
+                        return;
+                    }
+                };
+                self.addr
+                    .send(server::History {
+                        id: self.id,
+                        room,
+                        before,
+                        limit: server::HISTORY_PAGE_SIZE,
+                    })
+                    .into_actor(self)
+                    .then(|res, act, _| {
+                        match res {
+                            Ok(Some(messages)) => {
+                                for message in messages {
+                                    act.write(message.to_string());
+                                }
+                            }
+                            _ => act.write("!!! could not fetch history"),
+                        }
+                        actix::fut::ready(())
+                    })
+                    .wait(ctx);
+            }
+            Command::Message(body) => {
+                let room = match &self.room {
+                    Some(room) => room.clone(),
+                    None => {
+                        self.write("!!! join a room first");
+                        return;
+                    }
+                };
+                // send message to chat server
+                self.addr
+                    .send(server::Message {
+                        id: self.id,
+                        msg: body,
+                        room,
+                    })
+                    .into_actor(self)
+                    .then(|res, act, _| {
+                        match res {
+                            Ok(Ok(id)) => act.last_message = Some(id),
+                            Ok(Err(e)) => act.refused(e),
+                            _ => act.write("!!! could not send message"),
+                        }
+                        actix::fut::ready(())
+                    })
+                    .wait(ctx);
+            }
+        }
+    }
+}
+
+/// Handler for Message, chat server sends this message, we just send line to peer
+impl Handler<session::Message> for TextSession {
+    type Result = ();
+    fn handle(&mut self, msg: session::Message, _: &mut Self::Context) -> Self::Result {
+        match msg.0 {
+            ChatResponse::Message(message) => self.write(message.to_string()),
+            ChatResponse::DirectMessage(message) => self.write(message.to_string()),
+            ChatResponse::UserJoined {
+                room,
+                session,
+                name,
+            } => self.write(format!("!!! {} joined {}", who(session, name), room)),
+            ChatResponse::UserLeft {
+                room,
+                session,
+                name,
+            } => self.write(format!("!!! {} left {}", who(session, name), room)),
+            ChatResponse::UserDisconnected {
+                room,
+                session,
+                name,
+            } => self.write(format!(
+                "!!! {} disconnected from {}",
+                who(session, name),
+                room
+            )),
+            ChatResponse::Renamed {
+                session,
+                old_name,
+                new_name,
+            } => self.write(format!(
+                "!!! {} is now known as {}",
+                who(session, old_name),
+                new_name
+            )),
+            ChatResponse::Kicked {
+                room,
+                session,
+                name,
+                banned,
+            } => {
+                // server already took us out of the room
+                if session == self.id {
+                    self.rooms.remove(&room);
+                    if self.room.as_ref() == Some(&room) {
+                        self.room = self.rooms.iter().next().cloned();
+                    }
+                }
+                let how = if banned { "banned" } else { "kicked" };
+                self.write(format!(
+                    "!!! {} was {} from {}",
+                    who(session, name),
+                    how,
+                    room
+                ));
+            }
+            ChatResponse::Muted {
+                room,
+                session,
+                name,
+                muted,
+            } => {
+                let how = if muted { "muted" } else { "unmuted" };
+                self.write(format!(
+                    "!!! {} was {} in {}",
+                    who(session, name),
+                    how,
+                    room
+                ));
+            }
+            ChatResponse::Invited {
+                room,
+                session,
+                name,
+            } => self.write(format!(
+                "!!! {} invited you to {}",
+                who(session, name),
+                room
+            )),
+            ChatResponse::TopicChanged {
+                room,
+                session,
+                name,
+                topic,
+            } => match topic {
+                Some(topic) => self.write(format!(
+                    "!!! {} set the topic of {} to: {}",
+                    who(session, name),
+                    room,
+                    topic
+                )),
+                None => self.write(format!(
+                    "!!! {} cleared the topic of {}",
+                    who(session, name),
+                    room
+                )),
+            },
+            ChatResponse::MessageEdited(message) => self.write(format!("!!! edited: {}", message)),
+            ChatResponse::MessageDeleted {
+                room,
+                id,
+                session,
+                name,
+            } => self.write(format!(
+                "!!! {} deleted message {} in {}",
+                who(session, name),
+                id,
+                room
+            )),
+            ChatResponse::RoomCreated { room } => self.write(format!("!!! room created: {}", room)),
+            ChatResponse::Error { message, .. } => self.write(format!("!!! {}", message)),
+            ChatResponse::RoomRemoved { room } => self.write(format!("!!! room removed: {}", room)),
+            _ => (),
+        }
+    }
+}
+
+impl TextSession {
+    pub fn new(
+        addr: Addr<ChatServer>,
+        framed: actix::io::FramedWrite<String, WriteHalf<TcpStream>, LinesCodec>,
+        peer: Option<net::IpAddr>,
+    ) -> TextSession {
+        TextSession {
+            id: 0,
+            addr,
+            hb: Instant::now(),
+            rooms: iter::once("Main".to_owned()).collect(),
+            room: Some("Main".to_owned()),
+            last_message: None,
+            peer,
+            framed,
+        }
+    }
+
+    /// Join room `name` and make it the current room
+    fn join(
+        &mut self,
+        ctx: &mut Context<Self>,
+        name: String,
+        password: Option<String>,
+        visibility: Option<Visibility>,
+    ) {
+        rooms::join(
+            self.addr.clone(),
+            self.id,
+            name.clone(),
+            password,
+            visibility,
+        )
+        .into_actor(self)
+        .then(move |res, act, _| {
+            match res {
+                Ok(()) => {
+                    act.rooms.insert(name.clone());
+                    act.room = Some(name);
+                    act.write("joined");
+                }
+                Err(e @ RoomError::Flooding) => act.refused(e),
+                Err(e) => act.write(format!("!!! {}: {}", e, name)),
+            }
+            actix::fut::ready(())
+        })
+        .wait(ctx);
+    }
+
+    /// Tell peer whether `Register` or `Login` succeeded
+    fn logged_in(
+        &mut self,
+        ctx: &mut Context<Self>,
+        res: impl Future<Output = Result<String, AccountError>> + 'static,
+    ) {
+        res.into_actor(self)
+            .then(|res, act, _| {
+                match res {
+                    Ok(user) => act.write(format!("logged in as {}", user)),
+                    Err(AccountError::Throttled(e)) => act.refused(e),
+                    Err(e) => act.write(format!("!!! {}", e)),
+                }
+                actix::fut::ready(())
+            })
+            .wait(ctx);
+    }
+
+    /// Disconnect peer once it has been silent for `IDLE_TIMEOUT`
+    fn idle(&self, ctx: &mut Context<Self>) {
+        ctx.run_interval(IDLE_CHECK_INTERVAL, |act, ctx| {
+            if Instant::now().duration_since(act.hb) > IDLE_TIMEOUT {
+                println!("Text client idle for too long, disconnecting!");
+                act.write("!!! idle for too long, disconnecting");
+                // don't wait for the line to flush, peer may be long gone
+                ctx.stop();
+            }
+        });
+    }
+
+    /// Tell peer why its message or request was refused, closing the
+    /// connection if it keeps flooding
+    fn refused(&mut self, e: RoomError) {
+        self.write(format!("!!! {}", e));
+        if e == RoomError::Flooding {
+            // session stops once the line is flushed
+            self.framed.close();
+        }
+    }
+
+    /// Send one line to peer. Control characters are escaped, so names,
+    /// topics and messages of other peers can't break it into lines of their
+    /// own.
+    fn write(&mut self, line: impl Into<String>) {
+        self.framed.write(Escaped(&line.into()).to_string());
+    }
+}
+
+/// Name of a session, `#id` if it has none
+fn who(session: usize, name: Option<String>) -> String {
+    name.unwrap_or_else(|| format!("#{}", session))
+}
+
+/// Define tcp server that accepts line oriented connections and creates text sessions.
+pub fn text_server(s: &str, server: Addr<ChatServer>) {
+    let addr = net::SocketAddr::from_str(s).unwrap();
+
+    actix_web::rt::spawn(async move {
+        let mut listener = TcpListener::bind(&addr).await.unwrap();
+        let mut incoming = listener.incoming();
+
+        while let Some(stream) = incoming.next().await {
+            match stream {
+                Ok(stream) => {
+                    let server = server.clone();
+                    let peer = stream.peer_addr().ok().map(|addr| addr.ip());
+                    TextSession::create(|ctx| {
+                        let (r, w) = split(stream);
+                        TextSession::add_stream(
+                            FramedRead::new(r, LinesCodec::new_with_max_length(MAX_LINE_LENGTH)),
+                            ctx,
+                        );
+                        TextSession::new(
+                            server,
+                            actix::io::FramedWrite::new(w, LinesCodec::new(), ctx),
+                            peer,
+                        )
+                    });
+                }
+                Err(_) => return,
+            }
+        }
+    });
+}
@@ -0,0 +1,561 @@
+//! Property tests for the framed codecs.
+//!
+//! Whatever a peer sends, decoding must not panic, must keep the stream in
+//! sync and must not allocate beyond the maximum frame size.
+
+use actix_codec::{Decoder, Encoder};
+use bytes::{BufMut, BytesMut};
+use proptest::collection::vec;
+use proptest::option;
+use proptest::prelude::*;
+
+use tcp_chat::codec::{
+    ChatCodec, ChatMessage, ChatRequest, ChatResponse, ClientChatCodec, CodecError, Compression,
+    DirectMessage, Encoding, ErrorCode, Format, Member, Request, RoomInfo, SharedEncoding,
+    Transport, Visibility,
+};
+
+const MAX_FRAME_SIZE: usize = 64 * 1024;
+
+fn text() -> impl Strategy<Value = String> {
+    // long repetitive bodies go over the compression threshold
+    prop_oneof![".{0,64}", "[ab ]{1000,3000}"]
+}
+
+fn format() -> impl Strategy<Value = Format> {
+    prop_oneof![
+        Just(Format::Json),
+        Just(Format::MessagePack),
+        Just(Format::Cbor)
+    ]
+}
+
+fn compression() -> impl Strategy<Value = Compression> {
+    prop_oneof![Just(Compression::None), Just(Compression::Deflate)]
+}
+
+fn encoding() -> impl Strategy<Value = Encoding> {
+    (format(), compression()).prop_map(|(format, compression)| Encoding {
+        format,
+        compression,
+    })
+}
+
+fn error_code() -> impl Strategy<Value = ErrorCode> {
+    prop_oneof![
+        Just(ErrorCode::MalformedRequest),
+        Just(ErrorCode::UnknownCommand),
+        Just(ErrorCode::FrameTooLarge),
+        Just(ErrorCode::NotInRoom),
+        Just(ErrorCode::HandshakeRequired),
+        Just(ErrorCode::UnsupportedVersion),
+        Just(ErrorCode::InvalidRequest),
+        Just(ErrorCode::Internal),
+        Just(ErrorCode::NoSuchRoom),
+        Just(ErrorCode::NoSuchUser),
+        Just(ErrorCode::InvalidName),
+        Just(ErrorCode::NameTaken),
+        Just(ErrorCode::InvalidCredentials),
+        Just(ErrorCode::PermissionDenied),
+        Just(ErrorCode::Banned),
+        Just(ErrorCode::Muted),
+        Just(ErrorCode::NotInvited),
+        Just(ErrorCode::RateLimited),
+        Just(ErrorCode::NoSuchMessage),
+    ]
+}
+
+fn visibility() -> impl Strategy<Value = Visibility> {
+    prop_oneof![
+        Just(Visibility::Public),
+        Just(Visibility::Unlisted),
+        Just(Visibility::InviteOnly)
+    ]
+}
+
+fn request() -> impl Strategy<Value = Request> {
+    let body = prop_oneof![
+        (any::<u16>(), text(), vec(text(), 0..4)).prop_map(
+            |(version, client_name, capabilities)| ChatRequest::Hello {
+                version,
+                client_name,
+                capabilities,
+            }
+        ),
+        Just(ChatRequest::List),
+        (text(), option::of(text()), option::of(visibility())).prop_map(
+            |(room, password, visibility)| ChatRequest::Join {
+                room,
+                password,
+                visibility,
+            }
+        ),
+        text().prop_map(ChatRequest::Leave),
+        (text(), text()).prop_map(|(room, body)| ChatRequest::Message { room, body }),
+        (any::<u64>(), text()).prop_map(|(id, body)| ChatRequest::EditMessage { id, body }),
+        any::<u64>().prop_map(|id| ChatRequest::DeleteMessage { id }),
+        (text(), text()).prop_map(|(to, body)| ChatRequest::DirectMessage { to, body }),
+        text().prop_map(ChatRequest::SetName),
+        (text(), text()).prop_map(|(user, password)| ChatRequest::Register { user, password }),
+        (text(), text()).prop_map(|(user, password)| ChatRequest::Login { user, password }),
+        (text(), text()).prop_map(|(room, user)| ChatRequest::Kick { room, user }),
+        (text(), text()).prop_map(|(room, user)| ChatRequest::Ban { room, user }),
+        (text(), text()).prop_map(|(room, user)| ChatRequest::Unban { room, user }),
+        (text(), text()).prop_map(|(room, user)| ChatRequest::Mute { room, user }),
+        (text(), text()).prop_map(|(room, user)| ChatRequest::Unmute { room, user }),
+        (text(), text()).prop_map(|(room, user)| ChatRequest::Invite { room, user }),
+        (text(), visibility())
+            .prop_map(|(room, visibility)| ChatRequest::SetVisibility { room, visibility }),
+        (text(), option::of(text()))
+            .prop_map(|(room, password)| ChatRequest::SetPassword { room, password }),
+        (text(), option::of(text()))
+            .prop_map(|(room, topic)| ChatRequest::SetTopic { room, topic }),
+        (text(), option::of(text()))
+            .prop_map(|(room, description)| ChatRequest::SetDescription { room, description }),
+        (text(), text()).prop_map(|(room, user)| ChatRequest::AddModerator { room, user }),
+        (text(), text()).prop_map(|(room, user)| ChatRequest::RemoveModerator { room, user }),
+        text().prop_map(ChatRequest::Who),
+        Just(ChatRequest::Ping),
+        (text(), option::of(any::<u64>()), any::<usize>()).prop_map(|(room, before, limit)| {
+            ChatRequest::History {
+                room,
+                before,
+                limit,
+            }
+        }),
+    ];
+    (option::of(any::<u64>()), body).prop_map(|(id, body)| Request { id, body })
+}
+
+fn message() -> impl Strategy<Value = ChatMessage> {
+    (
+        any::<u64>(),
+        text(),
+        option::of(any::<usize>()),
+        option::of(text()),
+        option::of(text()),
+        any::<u64>(),
+        text(),
+        option::of(any::<u64>()),
+    )
+        .prop_map(
+            |(id, room, sender, sender_name, sender_account, timestamp, body, edited)| {
+                ChatMessage {
+                    id,
+                    room,
+                    sender,
+                    sender_name,
+                    sender_account,
+                    timestamp,
+                    body,
+                    edited,
+                }
+            },
+        )
+}
+
+fn direct_message() -> impl Strategy<Value = DirectMessage> {
+    (
+        any::<u64>(),
+        any::<usize>(),
+        option::of(text()),
+        any::<u64>(),
+        text(),
+    )
+        .prop_map(|(id, sender, sender_name, timestamp, body)| DirectMessage {
+            id,
+            sender,
+            sender_name,
+            timestamp,
+            body,
+        })
+}
+
+fn member() -> impl Strategy<Value = Member> {
+    let transport = prop_oneof![
+        Just(Transport::Tcp),
+        Just(Transport::WebSocket),
+        Just(Transport::Text)
+    ];
+    (any::<usize>(), option::of(text()), transport).prop_map(|(session, name, transport)| Member {
+        session,
+        name,
+        transport,
+    })
+}
+
+fn room_info() -> impl Strategy<Value = RoomInfo> {
+    (
+        text(),
+        option::of(text()),
+        option::of(text()),
+        any::<u64>(),
+        any::<usize>(),
+        any::<u64>(),
+    )
+        .prop_map(
+            |(name, topic, description, created, members, last_activity)| RoomInfo {
+                name,
+                topic,
+                description,
+                created,
+                members,
+                last_activity,
+            },
+        )
+}
+
+fn response() -> impl Strategy<Value = ChatResponse> {
+    prop_oneof![
+        Just(ChatResponse::Ping),
+        (
+            any::<u16>(),
+            any::<usize>(),
+            vec(text(), 0..4),
+            format(),
+            compression()
+        )
+            .prop_map(
+                |(version, session_id, server_capabilities, format, compression)| {
+                    ChatResponse::Welcome {
+                        version,
+                        session_id,
+                        server_capabilities,
+                        format,
+                        compression,
+                    }
+                }
+            ),
+        (vec(room_info(), 0..16), option::of(any::<u64>()))
+            .prop_map(|(rooms, request_id)| ChatResponse::Rooms { rooms, request_id }),
+        (text(), option::of(any::<u64>()))
+            .prop_map(|(room, request_id)| ChatResponse::Joined { room, request_id }),
+        (text(), option::of(any::<u64>()))
+            .prop_map(|(room, request_id)| ChatResponse::Left { room, request_id }),
+        option::of(any::<u64>()).prop_map(|request_id| ChatResponse::Ack { request_id }),
+        (any::<u64>(), option::of(any::<u64>()))
+            .prop_map(|(id, request_id)| ChatResponse::Sent { id, request_id }),
+        message().prop_map(ChatResponse::Message),
+        message().prop_map(ChatResponse::MessageEdited),
+        (text(), any::<u64>(), any::<usize>(), option::of(text())).prop_map(
+            |(room, id, session, name)| ChatResponse::MessageDeleted {
+                room,
+                id,
+                session,
+                name,
+            }
+        ),
+        direct_message().prop_map(ChatResponse::DirectMessage),
+        (text(), option::of(any::<u64>()))
+            .prop_map(|(user, request_id)| ChatResponse::LoggedIn { user, request_id }),
+        (text(), any::<usize>(), option::of(text())).prop_map(|(room, session, name)| {
+            ChatResponse::UserJoined {
+                room,
+                session,
+                name,
+            }
+        }),
+        (text(), any::<usize>(), option::of(text())).prop_map(|(room, session, name)| {
+            ChatResponse::UserLeft {
+                room,
+                session,
+                name,
+            }
+        }),
+        (text(), any::<usize>(), option::of(text())).prop_map(|(room, session, name)| {
+            ChatResponse::UserDisconnected {
+                room,
+                session,
+                name,
+            }
+        }),
+        (any::<usize>(), option::of(text()), text()).prop_map(|(session, old_name, new_name)| {
+            ChatResponse::Renamed {
+                session,
+                old_name,
+                new_name,
+            }
+        }),
+        (text(), vec(member(), 0..8), option::of(any::<u64>())).prop_map(
+            |(room, members, request_id)| ChatResponse::Members {
+                room,
+                members,
+                request_id,
+            }
+        ),
+        (text(), any::<usize>(), option::of(text()), any::<bool>()).prop_map(
+            |(room, session, name, banned)| ChatResponse::Kicked {
+                room,
+                session,
+                name,
+                banned,
+            }
+        ),
+        (text(), any::<usize>(), option::of(text()), any::<bool>()).prop_map(
+            |(room, session, name, muted)| ChatResponse::Muted {
+                room,
+                session,
+                name,
+                muted,
+            }
+        ),
+        (text(), any::<usize>(), option::of(text())).prop_map(|(room, session, name)| {
+            ChatResponse::Invited {
+                room,
+                session,
+                name,
+            }
+        }),
+        (
+            text(),
+            any::<usize>(),
+            option::of(text()),
+            option::of(text())
+        )
+            .prop_map(|(room, session, name, topic)| ChatResponse::TopicChanged {
+                room,
+                session,
+                name,
+                topic,
+            }),
+        text().prop_map(|room| ChatResponse::RoomCreated { room }),
+        text().prop_map(|room| ChatResponse::RoomRemoved { room }),
+        (text(), vec(message(), 0..4), option::of(any::<u64>())).prop_map(
+            |(room, messages, request_id)| ChatResponse::History {
+                room,
+                messages,
+                request_id,
+            }
+        ),
+        (error_code(), text(), option::of(any::<u64>())).prop_map(|(code, message, request_id)| {
+            ChatResponse::Error {
+                code,
+                message,
+                request_id,
+            }
+        }),
+    ]
+}
+
+fn shared(encoding: Encoding) -> SharedEncoding {
+    let shared = SharedEncoding::default();
+    shared.set(encoding);
+    shared
+}
+
+fn server_codec(encoding: Encoding) -> ChatCodec {
+    ChatCodec::new(MAX_FRAME_SIZE, shared(encoding))
+}
+
+fn client_codec(encoding: Encoding) -> ClientChatCodec {
+    ClientChatCodec::new(MAX_FRAME_SIZE, shared(encoding))
+}
+
+/// Decode frames until decoder wants more bytes, errors are collected too
+fn decode_all<D: Decoder<Error = CodecError>>(
+    codec: &mut D,
+    buf: &mut BytesMut,
+) -> Vec<Result<D::Item, CodecError>> {
+    let mut items = Vec::new();
+    loop {
+        match codec.decode(buf) {
+            Ok(Some(item)) => items.push(Ok(item)),
+            Ok(None) => return items,
+            Err(e) => {
+                assert!(e.is_recoverable());
+                items.push(Err(e));
+            }
+        }
+    }
+}
+
+fn encode_requests(encoding: Encoding, requests: &[Request]) -> BytesMut {
+    let mut codec = client_codec(encoding);
+    let mut buf = BytesMut::new();
+    for request in requests {
+        codec.encode(request.clone(), &mut buf).unwrap();
+    }
+    buf
+}
+
+proptest! {
+    #[test]
+    fn requests_round_trip(encoding in encoding(), requests in vec(request(), 1..8)) {
+        let mut buf = encode_requests(encoding, &requests);
+
+        let decoded = decode_all(&mut server_codec(encoding), &mut buf);
+        let decoded: Vec<Request> = decoded.into_iter().map(Result::unwrap).collect();
+        prop_assert_eq!(decoded, requests);
+        prop_assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn responses_round_trip(encoding in encoding(), responses in vec(response(), 1..8)) {
+        let mut codec = server_codec(encoding);
+        let mut buf = BytesMut::new();
+        for response in &responses {
+            codec.encode(response.clone(), &mut buf).unwrap();
+        }
+
+        let decoded = decode_all(&mut client_codec(encoding), &mut buf);
+        let decoded: Vec<ChatResponse> = decoded.into_iter().map(Result::unwrap).collect();
+        prop_assert_eq!(decoded, responses);
+        prop_assert!(buf.is_empty());
+    }
+}
+
+proptest! {
+    // every case decodes once per byte of input, keep the number of cases down
+    #![proptest_config(ProptestConfig::with_cases(32))]
+
+    #[test]
+    fn frames_split_at_every_byte(encoding in encoding(), requests in vec(request(), 1..4)) {
+        let buf = encode_requests(encoding, &requests);
+
+        for at in 0..=buf.len() {
+            let mut codec = server_codec(encoding);
+            let mut input = BytesMut::from(&buf[..at]);
+            let mut decoded = decode_all(&mut codec, &mut input);
+            input.extend_from_slice(&buf[at..]);
+            decoded.extend(decode_all(&mut codec, &mut input));
+
+            let decoded: Vec<Request> = decoded.into_iter().map(Result::unwrap).collect();
+            prop_assert_eq!(&decoded, &requests);
+        }
+    }
+
+    #[test]
+    fn truncated_frames_wait_for_more(encoding in encoding(), request in request()) {
+        let buf = encode_requests(encoding, &[request]);
+
+        for at in 0..buf.len() {
+            let mut input = BytesMut::from(&buf[..at]);
+            prop_assert!(decode_all(&mut server_codec(encoding), &mut input).is_empty());
+        }
+    }
+}
+
+proptest! {
+    #[test]
+    fn garbage_does_not_panic(encoding in encoding(), garbage in vec(any::<u8>(), 0..512)) {
+        let mut input = BytesMut::from(&garbage[..]);
+        decode_all(&mut server_codec(encoding), &mut input);
+
+        let mut input = BytesMut::from(&garbage[..]);
+        decode_all(&mut client_codec(encoding), &mut input);
+    }
+
+    #[test]
+    fn garbage_payload_keeps_stream_in_sync(
+        encoding in encoding(),
+        garbage in vec(any::<u8>(), 0..512),
+        request in request(),
+    ) {
+        let mut input = BytesMut::new();
+        input.put_u32(garbage.len() as u32);
+        input.extend_from_slice(&garbage);
+        input.extend_from_slice(&encode_requests(encoding, std::slice::from_ref(&request)));
+
+        let decoded = decode_all(&mut server_codec(encoding), &mut input);
+        prop_assert_eq!(decoded.last().unwrap().as_ref().unwrap(), &request);
+    }
+
+    #[test]
+    fn oversized_frames_are_skipped(
+        encoding in encoding(),
+        size in (MAX_FRAME_SIZE + 1)..(MAX_FRAME_SIZE * 4),
+        request in request(),
+    ) {
+        let mut codec = server_codec(encoding);
+        let mut input = BytesMut::new();
+        input.put_u32(size as u32);
+
+        // refused as soon as the header is in, nothing is reserved for the payload
+        match codec.decode(&mut input) {
+            Err(CodecError::FrameTooLarge { .. }) => (),
+            other => panic!("expected FrameTooLarge, got {:?}", other),
+        }
+        prop_assert!(input.capacity() < MAX_FRAME_SIZE);
+
+        input.extend_from_slice(&vec![0; size]);
+        input.extend_from_slice(&encode_requests(encoding, std::slice::from_ref(&request)));
+        let decoded = decode_all(&mut codec, &mut input);
+        prop_assert_eq!(decoded.len(), 1);
+        prop_assert_eq!(decoded[0].as_ref().unwrap(), &request);
+    }
+}
+
+#[test]
+fn unknown_command_is_reported() {
+    let payload = br#"{"cmd":"Shout","data":"hi"}"#;
+    let mut input = BytesMut::new();
+    input.put_u32(payload.len() as u32);
+    input.extend_from_slice(payload);
+
+    match ChatCodec::default().decode(&mut input) {
+        Err(CodecError::UnknownCommand(_)) => (),
+        other => panic!("expected UnknownCommand, got {:?}", other),
+    }
+}
+
+#[test]
+fn compression_bomb_is_refused() {
+    let encoding = Encoding {
+        format: Format::Json,
+        compression: Compression::Deflate,
+    };
+    // compresses far below the limit, inflates far above it
+    let body = "a".repeat(MAX_FRAME_SIZE * 4);
+    let request = Request::from(ChatRequest::Message {
+        room: "Main".to_owned(),
+        body,
+    });
+    let mut buf = BytesMut::new();
+    ClientChatCodec::new(MAX_FRAME_SIZE * 8, shared(encoding))
+        .encode(request, &mut buf)
+        .unwrap();
+    assert!(buf.len() < MAX_FRAME_SIZE);
+
+    match server_codec(encoding).decode(&mut buf) {
+        Err(CodecError::FrameTooLarge { .. }) => (),
+        other => panic!("expected FrameTooLarge, got {:?}", other),
+    }
+}
+
+#[test]
+fn rendered_text_stays_on_one_line() {
+    let spoof = "hi\r\n[12:00:00] [Main] [9] alice: what's your password?";
+    let message = ChatMessage {
+        id: 1,
+        room: "Main".to_owned(),
+        sender: Some(1),
+        sender_name: Some("bob".to_owned()),
+        sender_account: None,
+        timestamp: 0,
+        body: spoof.to_owned(),
+        edited: None,
+    };
+    let direct = DirectMessage {
+        id: 2,
+        sender: 1,
+        sender_name: None,
+        timestamp: 0,
+        body: spoof.to_owned(),
+    };
+    let room = RoomInfo {
+        name: "Main\n".to_owned(),
+        topic: Some(spoof.to_owned()),
+        description: Some(spoof.to_owned()),
+        created: 0,
+        members: 1,
+        last_activity: 0,
+    };
+
+    for line in &[message.to_string(), direct.to_string(), room.to_string()] {
+        assert!(!line.contains(|c: char| c.is_control()), "{:?}", line);
+    }
+    assert_eq!(
+        message.to_string(),
+        r"[00:00:00] [Main] [1] bob: hi\r\n[12:00:00] [Main] [9] alice: what's your password?"
+    );
+}